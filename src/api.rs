@@ -0,0 +1,649 @@
+use crate::user;
+use md5::{Digest, Md5};
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36";
+const NAV_URL: &str = "https://api.bilibili.com/x/web-interface/nav";
+const SPACE_VIDEOS_URL: &str = "https://api.bilibili.com/x/space/wbi/arc/search";
+const SERIES_URL: &str = "https://api.bilibili.com/x/series/series";
+const SERIES_ARCHIVES_URL: &str = "https://api.bilibili.com/x/series/archives";
+const FAV_FOLDERS_URL: &str = "https://api.bilibili.com/x/v3/fav/folder/created/list-all";
+const FAV_MEDIAS_URL: &str = "https://api.bilibili.com/x/v3/fav/resource/list";
+const VIDEO_VIEW_URL: &str = "https://api.bilibili.com/x/web-interface/view";
+const BANGUMI_SEASON_URL: &str = "https://api.bilibili.com/pgc/view/web/season";
+const BANGUMI_MEDIA_URL: &str = "https://api.bilibili.com/pgc/review/user";
+const COOKIE_INFO_URL: &str = "https://passport.bilibili.com/x/passport-login/web/cookie/info";
+const LOGOUT_URL: &str = "https://passport.bilibili.com/login/exit/v2";
+const WATCH_LATER_URL: &str = "https://api.bilibili.com/x/v2/history/toview";
+const WATCH_LATER_DEL_URL: &str = "https://api.bilibili.com/x/v2/history/toview/del";
+const PLAYURL_URL: &str = "https://api.bilibili.com/x/player/wbi/playurl";
+/// dash格式, 包含hevc/av1/4K等全部视频流
+const PLAYURL_FNVAL: i64 = 4048;
+
+/// WBI签名打乱img_key和sub_key使用的顺序
+const MIXIN_KEY_ENC_TAB: [usize; 64] = [
+    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9, 42, 19, 29,
+    28, 14, 39, 12, 38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0, 1, 60, 51, 30, 4, 22, 25,
+    54, 21, 56, 59, 6, 63, 57, 62, 11, 36, 20, 34, 44, 52,
+];
+
+/// 每次运行只获取一次签名密钥
+static MIXIN_KEY: OnceCell<String> = OnceCell::new();
+
+/// 接口返回的错误, 保留code用于区分原因
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    pub(crate) code: i64,
+    pub(crate) message: String,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "接口错误 ({}) : {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// dash格式的一个视频流
+#[derive(Clone, Debug)]
+pub(crate) struct DashVideo {
+    /// 清晰度
+    pub(crate) id: i64,
+    /// 编码 : 7 avc, 12 hevc, 13 av1, 未知时为0
+    pub(crate) codecid: i64,
+    pub(crate) base_url: String,
+}
+
+impl From<&bilirust::Video> for DashVideo {
+    fn from(video: &bilirust::Video) -> Self {
+        DashVideo {
+            id: video.id,
+            codecid: 0,
+            base_url: video.base_url.clone(),
+        }
+    }
+}
+
+/// 投稿列表中的一个视频
+#[derive(Clone, Debug)]
+pub(crate) struct SpaceVideo {
+    pub(crate) bvid: String,
+    pub(crate) title: String,
+    pub(crate) author: String,
+    /// 发布时间 (秒)
+    pub(crate) created: i64,
+    /// 时长 (秒)
+    pub(crate) length: i64,
+}
+
+/// 投稿列表的一页
+pub(crate) struct SpacePage {
+    pub(crate) videos: Vec<SpaceVideo>,
+    /// 符合条件的视频总数
+    pub(crate) count: i64,
+}
+
+/// 请求bilirust没有封装的接口, 已登录时带上SESSDATA, 返回data字段
+pub(crate) async fn get_json(url: &str, query: &str) -> crate::Result<Value> {
+    response_data(get_raw(url, query).await?)
+}
+
+/// code为0时返回data字段 (番剧接口为result), 否则返回接口的错误信息
+fn response_data(value: Value) -> crate::Result<Value> {
+    match value["code"].as_i64() {
+        Some(0) if value["data"].is_null() => Ok(value["result"].clone()),
+        Some(0) => Ok(value["data"].clone()),
+        code => Err(anyhow::Error::new(ApiError {
+            code: code.unwrap_or_default(),
+            message: value["message"].as_str().unwrap_or_default().to_owned(),
+        })),
+    }
+}
+
+async fn get_raw(url: &str, query: &str) -> crate::Result<Value> {
+    let url = if query.is_empty() {
+        url.to_owned()
+    } else {
+        format!("{}?{}", url, query)
+    };
+    let mut request = reqwest::Client::new()
+        .get(url)
+        .header("User-Agent", USER_AGENT)
+        .header("Referer", "https://www.bilibili.com/");
+    if let Some(token) = user::load_web_token().await? {
+        request = request.header("Cookie", format!("SESSDATA={}", token.sessdata));
+    }
+    let text = request.send().await?.error_for_status()?.text().await?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+/// 需要WBI签名的接口, 参数会被排序并加上wts和w_rid
+pub(crate) async fn get_json_wbi(url: &str, params: &[(&str, String)]) -> crate::Result<Value> {
+    let mixin_key = mixin_key().await?;
+    let wts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    get_json(url, &wbi_sign(params, &mixin_key, wts)).await
+}
+
+/// 排序参数并加上wts和w_rid, 返回签名后的查询字符串
+fn wbi_sign(params: &[(&str, String)], mixin_key: &str, wts: u64) -> String {
+    let mut params: Vec<(&str, String)> = params.to_vec();
+    params.push(("wts", wts.to_string()));
+    params.sort_by(|a, b| a.0.cmp(b.0));
+    let query = params
+        .iter()
+        .map(|(k, v)| {
+            let v: String = v.chars().filter(|c| !"!'()*".contains(*c)).collect();
+            format!("{}={}", encode_component(k), encode_component(&v))
+        })
+        .collect::<Vec<String>>()
+        .join("&");
+    let w_rid = hex(&Md5::digest(format!("{}{}", query, mixin_key)));
+    format!("{}&w_rid={}", query, w_rid)
+}
+
+async fn mixin_key() -> crate::Result<String> {
+    if let Some(key) = MIXIN_KEY.get() {
+        return Ok(key.clone());
+    }
+    // 未登录时nav返回-101, 但依然包含wbi_img
+    let nav = get_raw(NAV_URL, "").await?;
+    let img = &nav["data"]["wbi_img"];
+    let key = mixin_key_from(
+        img["img_url"].as_str().unwrap_or_default(),
+        img["sub_url"].as_str().unwrap_or_default(),
+    )
+    .ok_or_else(|| anyhow::Error::msg("获取接口签名密钥失败"))?;
+    let _ = MIXIN_KEY.set(key.clone());
+    Ok(key)
+}
+
+/// 由wbi_img中的两个图片地址打乱得到签名密钥
+fn mixin_key_from(img_url: &str, sub_url: &str) -> Option<String> {
+    let raw = format!("{}{}", url_stem(img_url), url_stem(sub_url));
+    if raw.len() < 64 {
+        return None;
+    }
+    let raw = raw.as_bytes();
+    Some(
+        MIXIN_KEY_ENC_TAB
+            .iter()
+            .take(32)
+            .map(|i| raw[*i] as char)
+            .collect(),
+    )
+}
+
+/// https://.../xxxx.png 中的 xxxx
+fn url_stem(url: &str) -> &str {
+    let name = url.rsplit('/').next().unwrap_or_default();
+    name.split('.').next().unwrap_or_default()
+}
+
+fn encode_component(s: &str) -> String {
+    let mut result = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                result.push(b as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    result
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// "mm:ss" 或 "hh:mm:ss" 转为秒
+fn parse_length(length: &str) -> i64 {
+    length
+        .split(':')
+        .fold(0, |acc, x| acc * 60 + x.trim().parse::<i64>().unwrap_or(0))
+}
+
+/// UP主的投稿视频, order : pubdate (最新发布) / click (最多播放) / stow (最多收藏)
+pub(crate) async fn space_videos(
+    mid: i64,
+    page: i64,
+    page_size: i64,
+    order: &str,
+    keyword: &str,
+) -> crate::Result<SpacePage> {
+    let data = get_json_wbi(
+        SPACE_VIDEOS_URL,
+        &[
+            ("mid", mid.to_string()),
+            ("pn", page.to_string()),
+            ("ps", page_size.to_string()),
+            ("order", order.to_owned()),
+            ("keyword", keyword.to_owned()),
+        ],
+    )
+    .await?;
+    let videos = data["list"]["vlist"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .map(|x| SpaceVideo {
+                    bvid: x["bvid"].as_str().unwrap_or_default().to_owned(),
+                    title: x["title"].as_str().unwrap_or_default().to_owned(),
+                    author: x["author"].as_str().unwrap_or_default().to_owned(),
+                    created: x["created"].as_i64().unwrap_or_default(),
+                    length: parse_length(x["length"].as_str().unwrap_or_default()),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(SpacePage {
+        videos,
+        count: data["page"]["count"].as_i64().unwrap_or_default(),
+    })
+}
+
+/// 视频列表中的一个视频
+#[derive(Clone, Debug)]
+pub(crate) struct Archive {
+    pub(crate) bvid: String,
+    pub(crate) title: String,
+}
+
+/// 视频列表 (seriesdetail) 的名称
+pub(crate) async fn series_name(series_id: i64) -> crate::Result<String> {
+    let data = get_json(SERIES_URL, &format!("series_id={}", series_id)).await?;
+    Ok(data["meta"]["name"].as_str().unwrap_or_default().to_owned())
+}
+
+/// 视频列表 (seriesdetail) 的一页, 同时返回视频总数
+pub(crate) async fn series_archives(
+    mid: i64,
+    series_id: i64,
+    page: i64,
+    page_size: i64,
+) -> crate::Result<(Vec<Archive>, i64)> {
+    let data = get_json(
+        SERIES_ARCHIVES_URL,
+        &format!(
+            "mid={}&series_id={}&only_normal=true&sort=desc&pn={}&ps={}",
+            mid, series_id, page, page_size
+        ),
+    )
+    .await?;
+    let archives = data["archives"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .map(|x| Archive {
+                    bvid: x["bvid"].as_str().unwrap_or_default().to_owned(),
+                    title: x["title"].as_str().unwrap_or_default().to_owned(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((archives, data["page"]["total"].as_i64().unwrap_or_default()))
+}
+
+/// 收藏夹
+#[derive(Clone, Debug)]
+pub(crate) struct FavFolder {
+    pub(crate) id: i64,
+    pub(crate) title: String,
+    pub(crate) media_count: i64,
+}
+
+/// 收藏夹中的一项, attr不为0时表示已失效
+#[derive(Clone, Debug)]
+pub(crate) struct FavMedia {
+    pub(crate) bvid: String,
+    pub(crate) title: String,
+    /// 2 为视频
+    pub(crate) media_type: i64,
+    pub(crate) attr: i64,
+}
+
+/// 收藏夹的一页
+pub(crate) struct FavPage {
+    pub(crate) folder: FavFolder,
+    pub(crate) medias: Vec<FavMedia>,
+    pub(crate) has_more: bool,
+}
+
+/// 用户创建的全部收藏夹
+pub(crate) async fn fav_folders(mid: i64) -> crate::Result<Vec<FavFolder>> {
+    let data = get_json(FAV_FOLDERS_URL, &format!("up_mid={}", mid)).await?;
+    Ok(data["list"]
+        .as_array()
+        .map(|list| list.iter().map(fav_folder).collect())
+        .unwrap_or_default())
+}
+
+fn fav_folder(value: &Value) -> FavFolder {
+    FavFolder {
+        id: value["id"].as_i64().unwrap_or_default(),
+        title: value["title"].as_str().unwrap_or_default().to_owned(),
+        media_count: value["media_count"].as_i64().unwrap_or_default(),
+    }
+}
+
+/// 收藏夹中的视频, 私密收藏夹需要登录
+pub(crate) async fn fav_medias(media_id: i64, page: i64, page_size: i64) -> crate::Result<FavPage> {
+    let data = get_json(
+        FAV_MEDIAS_URL,
+        &format!(
+            "media_id={}&pn={}&ps={}&platform=web",
+            media_id, page, page_size
+        ),
+    )
+    .await?;
+    let medias = data["medias"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .map(|x| FavMedia {
+                    bvid: x["bvid"].as_str().unwrap_or_default().to_owned(),
+                    title: x["title"].as_str().unwrap_or_default().to_owned(),
+                    media_type: x["type"].as_i64().unwrap_or_default(),
+                    attr: x["attr"].as_i64().unwrap_or_default(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(FavPage {
+        folder: fav_folder(&data["info"]),
+        medias,
+        has_more: data["has_more"].as_bool().unwrap_or_default(),
+    })
+}
+
+/// 稍后再看中的一个视频
+#[derive(Clone, Debug)]
+pub(crate) struct WatchLater {
+    pub(crate) aid: i64,
+    pub(crate) bvid: String,
+    pub(crate) title: String,
+}
+
+/// 当前账号的稍后再看列表
+pub(crate) async fn watch_later() -> crate::Result<Vec<WatchLater>> {
+    let data = get_json(WATCH_LATER_URL, "").await?;
+    Ok(data["list"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .map(|x| WatchLater {
+                    aid: x["aid"].as_i64().unwrap_or_default(),
+                    bvid: x["bvid"].as_str().unwrap_or_default().to_owned(),
+                    title: x["title"].as_str().unwrap_or_default().to_owned(),
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// 从稍后再看中删除
+pub(crate) async fn watch_later_remove(aid: i64) -> crate::Result<()> {
+    post_form(WATCH_LATER_DEL_URL, &[("aid", aid.to_string())]).await?;
+    Ok(())
+}
+
+/// 需要登录的POST接口, 自动带上csrf (bili_jct)
+async fn post_form(url: &str, params: &[(&str, String)]) -> crate::Result<Value> {
+    let token = user::load_web_token()
+        .await?
+        .ok_or_else(|| anyhow::Error::msg("需要登录!"))?;
+    let mut form: Vec<(&str, String)> = params.to_vec();
+    form.push(("csrf", token.bili_jct.clone()));
+    let text = reqwest::Client::new()
+        .post(url)
+        .header("User-Agent", USER_AGENT)
+        .header("Referer", "https://www.bilibili.com/")
+        .header(
+            "Cookie",
+            format!("SESSDATA={}; bili_jct={}", token.sessdata, token.bili_jct),
+        )
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    response_data(serde_json::from_str(&text)?)
+}
+
+/// 视频的基本信息
+pub(crate) struct VideoView {
+    pub(crate) title: String,
+    pub(crate) cid: i64,
+    /// 封面图片地址
+    pub(crate) pic: String,
+    /// 番剧的视频会跳转到ep
+    pub(crate) ep_id: Option<i64>,
+}
+
+pub(crate) async fn video_view(bvid: &str) -> crate::Result<VideoView> {
+    let data = get_json(VIDEO_VIEW_URL, &format!("bvid={}", bvid)).await?;
+    let ep_id = data["redirect_url"]
+        .as_str()
+        .and_then(|x| x.rsplit("/ep").next())
+        .and_then(|x| {
+            x.chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+                .parse()
+                .ok()
+        });
+    Ok(VideoView {
+        title: data["title"].as_str().unwrap_or_default().to_owned(),
+        cid: data["cid"].as_i64().unwrap_or_default(),
+        pic: data["pic"].as_str().unwrap_or_default().to_owned(),
+        ep_id,
+    })
+}
+
+/// 番剧的一集
+pub(crate) struct BangumiEpisode {
+    pub(crate) ep_id: i64,
+    pub(crate) aid: i64,
+    pub(crate) bvid: String,
+    pub(crate) cid: i64,
+    pub(crate) title: String,
+    /// 角标, 例如 会员 / 付费 / 预告 / 限免
+    pub(crate) badge: String,
+    /// 2 免费, 12 付费, 13 大会员
+    pub(crate) status: i64,
+    pub(crate) area_limit: bool,
+}
+
+/// 番剧正片以外的一部分
+pub(crate) struct BangumiSection {
+    pub(crate) title: String,
+    pub(crate) episodes: Vec<BangumiEpisode>,
+}
+
+/// 番剧的一季
+pub(crate) struct BangumiSeason {
+    pub(crate) season_id: i64,
+    pub(crate) title: String,
+    pub(crate) episodes: Vec<BangumiEpisode>,
+    /// 正片以外的部分, 例如 PV / OP / ED / 花絮
+    pub(crate) sections: Vec<BangumiSection>,
+    /// 当前账号已购买
+    pub(crate) paid: bool,
+}
+
+/// 番剧信息, query 为 ep_id=N 或 season_id=N
+pub(crate) async fn bangumi_season(query: &str) -> crate::Result<BangumiSeason> {
+    let data = get_json(BANGUMI_SEASON_URL, query).await?;
+    let sections = data["section"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .map(|x| BangumiSection {
+                    title: x["title"].as_str().unwrap_or_default().to_owned(),
+                    episodes: bangumi_episodes(&x["episodes"]),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(BangumiSeason {
+        season_id: data["season_id"].as_i64().unwrap_or_default(),
+        title: data["title"].as_str().unwrap_or_default().to_owned(),
+        episodes: bangumi_episodes(&data["episodes"]),
+        sections,
+        paid: data["user_status"]["pay"].as_i64() == Some(1),
+    })
+}
+
+fn bangumi_episodes(value: &Value) -> Vec<BangumiEpisode> {
+    value
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .map(|x| BangumiEpisode {
+                    ep_id: x["id"].as_i64().unwrap_or_default(),
+                    aid: x["aid"].as_i64().unwrap_or_default(),
+                    bvid: x["bvid"].as_str().unwrap_or_default().to_owned(),
+                    cid: x["cid"].as_i64().unwrap_or_default(),
+                    title: format!(
+                        "{} {}",
+                        x["title"].as_str().unwrap_or_default(),
+                        x["long_title"].as_str().unwrap_or_default()
+                    )
+                    .trim()
+                    .to_owned(),
+                    badge: x["badge"].as_str().unwrap_or_default().to_owned(),
+                    status: x["status"].as_i64().unwrap_or_default(),
+                    area_limit: x["rights"]["area_limit"].as_i64().unwrap_or_default() != 0,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// 带有编码信息的dash视频流, 用于按编码选择
+pub(crate) async fn dash_videos(bvid: &str, cid: i64) -> crate::Result<Vec<DashVideo>> {
+    let data = get_json_wbi(
+        PLAYURL_URL,
+        &[
+            ("bvid", bvid.to_owned()),
+            ("cid", cid.to_string()),
+            ("qn", "127".to_owned()),
+            ("fnval", PLAYURL_FNVAL.to_string()),
+            ("fourk", "1".to_owned()),
+        ],
+    )
+    .await?;
+    Ok(data["dash"]["video"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .map(|x| DashVideo {
+                    id: x["id"].as_i64().unwrap_or_default(),
+                    codecid: x["codecid"].as_i64().unwrap_or_default(),
+                    base_url: x["baseUrl"]
+                        .as_str()
+                        .or_else(|| x["base_url"].as_str())
+                        .unwrap_or_default()
+                        .to_owned(),
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// 番剧介绍页 (md) 对应的ss
+pub(crate) async fn md_season_id(media_id: i64) -> crate::Result<i64> {
+    let data = get_json(BANGUMI_MEDIA_URL, &format!("media_id={}", media_id)).await?;
+    data["media"]["season_id"]
+        .as_i64()
+        .ok_or_else(|| anyhow::Error::msg(format!("未找到番剧 : md{}", media_id)))
+}
+
+/// 当前账号的等级和大会员信息
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Account {
+    pub(crate) is_login: bool,
+    pub(crate) name: String,
+    pub(crate) mid: i64,
+    pub(crate) level: i64,
+    pub(crate) vip: bool,
+    /// 0 : 不是大会员, 1 : 月度大会员, 2 : 年度及以上大会员
+    pub(crate) vip_type: i64,
+    pub(crate) vip_label: String,
+    /// 大会员到期时间 (毫秒)
+    pub(crate) vip_due: i64,
+}
+
+/// 当前账号的信息, 未登录时 is_login 为 false, 一般通过 user::account 使用缓存的结果
+pub(crate) async fn account() -> crate::Result<Account> {
+    let value = get_raw(NAV_URL, "").await?;
+    let data = &value["data"];
+    Ok(Account {
+        is_login: data["isLogin"].as_bool().unwrap_or(false),
+        name: data["uname"].as_str().unwrap_or_default().to_owned(),
+        mid: data["mid"].as_i64().unwrap_or_default(),
+        level: data["level_info"]["current_level"]
+            .as_i64()
+            .unwrap_or_default(),
+        vip: data["vipStatus"].as_i64() == Some(1),
+        vip_type: data["vipType"].as_i64().unwrap_or_default(),
+        vip_label: data["vip_label"]["text"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned(),
+        vip_due: data["vipDueDate"].as_i64().unwrap_or_default(),
+    })
+}
+
+/// 服务器是否建议刷新cookie
+pub(crate) async fn cookie_needs_refresh(csrf: &str) -> crate::Result<bool> {
+    let data = get_json(COOKIE_INFO_URL, &format!("csrf={}", csrf)).await?;
+    Ok(data["refresh"].as_bool().unwrap_or(false))
+}
+
+/// 通知服务器退出登录, 使当前的SESSDATA失效
+pub(crate) async fn logout(csrf: &str) -> crate::Result<()> {
+    post_form(LOGOUT_URL, &[("biliCSRF", csrf.to_owned())]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMG_URL: &str = "https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png";
+    const SUB_URL: &str = "https://i0.hdslb.com/bfs/wbi/4932caff0ff746eab6f01bf08b70ac45.png";
+
+    #[test]
+    fn mixin_key_from_urls() {
+        assert_eq!(
+            mixin_key_from(IMG_URL, SUB_URL).unwrap(),
+            "ea1db124af3c7062474693fa704f4ff8"
+        );
+        assert!(mixin_key_from("", SUB_URL).is_none());
+    }
+
+    #[test]
+    fn wbi_sign_sorts_and_signs() {
+        let params = [
+            ("foo", "114".to_owned()),
+            ("bar", "514".to_owned()),
+            ("zab", "1919810".to_owned()),
+        ];
+        assert_eq!(
+            wbi_sign(&params, "ea1db124af3c7062474693fa704f4ff8", 1702204169),
+            "bar=514&foo=114&wts=1702204169&zab=1919810&w_rid=8f6f2b5b3d485fe1886cec6a0be8c5d4"
+        );
+    }
+
+    #[test]
+    fn wbi_sign_filters_and_encodes() {
+        let params = [("keyword", "a b!(c)".to_owned())];
+        let query = wbi_sign(&params, "ea1db124af3c7062474693fa704f4ff8", 1);
+        assert!(query.starts_with("keyword=a%20bc&wts=1&w_rid="));
+    }
+}
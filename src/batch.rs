@@ -0,0 +1,145 @@
+use crate::{cli, resolver};
+use anyhow::Context;
+use console::{pad_str, style, Alignment, Emoji};
+use std::io::BufRead;
+
+/// 一个网址的下载结果
+struct BatchResult {
+    url: String,
+    error: Option<String>,
+}
+
+/// 读取要下载的网址 : 参数 - 表示从标准输入读取, 批量文件每行一个网址, # 开头的行是注释
+pub(crate) fn read_inputs(
+    urls: &[String],
+    batch_file: &Option<String>,
+) -> crate::Result<Vec<String>> {
+    read_inputs_from(urls, batch_file, std::io::stdin().lock())
+}
+
+fn read_inputs_from(
+    urls: &[String],
+    batch_file: &Option<String>,
+    stdin: impl BufRead,
+) -> crate::Result<Vec<String>> {
+    let mut stdin = Some(stdin);
+    let mut lines = vec![];
+    for url in urls {
+        if url == "-" {
+            // 标准输入只读取一次
+            if let Some(stdin) = stdin.take() {
+                for line in stdin.lines() {
+                    lines.push(line?);
+                }
+            }
+        } else {
+            lines.push(url.clone());
+        }
+    }
+    if let Some(path) = batch_file {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("无法读取批量文件 : {}", path))?;
+        lines.extend(content.lines().map(|x| x.to_owned()));
+    }
+    Ok(lines
+        .into_iter()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .collect())
+}
+
+/// 依次下载每个网址, 单个失败不影响其他网址, 最后打印汇总, 有失败时返回错误
+pub(crate) async fn download_all(urls: Vec<String>) -> crate::Result<()> {
+    if urls.is_empty() {
+        return Err(anyhow::Error::msg("没有要下载的网址"));
+    }
+    let mut results = vec![];
+    for (i, url) in urls.iter().enumerate() {
+        println!();
+        println!("{}[{}/{}] {}", Emoji("📦 ", ""), i + 1, urls.len(), url);
+        let result = match cli::check_download_url(url) {
+            Ok(url) => resolver::download(url, false).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            println!("{}", style(err).red());
+        }
+        results.push(BatchResult {
+            url: url.clone(),
+            error: result.err().map(|x| x.to_string()),
+        });
+    }
+    print_summary(&results);
+    let failed = results.iter().filter(|x| x.error.is_some()).count();
+    if failed > 0 {
+        return Err(anyhow::Error::msg(format!("{} 个网址下载失败", failed)));
+    }
+    Ok(())
+}
+
+fn print_summary(results: &[BatchResult]) {
+    let failed = results.iter().filter(|x| x.error.is_some()).count();
+    let width = results
+        .iter()
+        .map(|x| console::measure_text_width(&x.url))
+        .max()
+        .unwrap_or(0);
+    println!();
+    println!(
+        "{}批量下载结束 : 成功 {} , 失败 {}",
+        Emoji("✨", ""),
+        results.len() - failed,
+        failed
+    );
+    println!();
+    for result in results {
+        let url = pad_str(&result.url, width, Alignment::Left, None);
+        match &result.error {
+            None => println!("  {}  {}", style("成功").green(), url),
+            Some(err) => println!(
+                "  {}  {}  {}",
+                style("失败").red(),
+                url,
+                style(err.lines().next().unwrap_or_default()).red()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn urls_from_arguments() {
+        let inputs = read_inputs_from(&strings(&["a", " b ", ""]), &None, "".as_bytes()).unwrap();
+        assert_eq!(inputs, strings(&["a", "b"]));
+    }
+
+    #[test]
+    fn dash_reads_stdin_once() {
+        let stdin = "x\n\n# comment\n  y  \n".as_bytes();
+        let inputs = read_inputs_from(&strings(&["a", "-", "b", "-"]), &None, stdin).unwrap();
+        assert_eq!(inputs, strings(&["a", "x", "y", "b"]));
+    }
+
+    #[test]
+    fn batch_file_skips_comments_and_blank_lines() {
+        let path = std::env::temp_dir().join(format!("bili-batch-test-{}.txt", std::process::id()));
+        std::fs::write(&path, "# list\nurl1\n\n   \n  # indented comment\nurl2\n").unwrap();
+        let batch_file = Some(path.to_string_lossy().into_owned());
+        let inputs = read_inputs_from(&strings(&["a"]), &batch_file, "".as_bytes()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(inputs, strings(&["a", "url1", "url2"]));
+    }
+
+    #[test]
+    fn missing_batch_file() {
+        let batch_file = Some("/nonexistent/bili-batch.txt".to_owned());
+        assert!(read_inputs_from(&[], &batch_file, "".as_bytes()).is_err());
+    }
+}
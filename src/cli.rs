@@ -1,5 +1,10 @@
-use crate::{download, user};
+use crate::{
+    batch, config, download, fav, ffmpeg, id, migration, profile, resolver, secret, serve,
+    subscribe, tui, user,
+};
+use chrono::NaiveDate;
 use clap::{CommandFactory, Parser, Subcommand};
+use console::style;
 use dialoguer::Input;
 use once_cell::sync::OnceCell;
 
@@ -10,73 +15,436 @@ static CLI: OnceCell<Cli> = OnceCell::new();
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// 使用的账号, 默认为 bili profile use 选择的账号, 每个账号有单独的登录信息和设置
+    #[arg(long, global = true, value_parser = check_profile_name)]
+    profile: Option<String>,
+
+    /// 下载保存的目录 (配置项 output_dir)
+    #[arg(long, global = true)]
+    output_dir: Option<String>,
+
+    /// 默认清晰度, 例如 80 为1080P (配置项 quality)
+    #[arg(long, global = true)]
+    quality: Option<u64>,
+
+    /// 每个文件的下载限速, 单位KB/s (配置项 rate_limit)
+    #[arg(long, global = true)]
+    rate_limit: Option<u64>,
+
+    /// 代理地址 (配置项 proxy)
+    #[arg(long, global = true)]
+    proxy: Option<String>,
+
+    /// 优先下载的视频编码 (配置项 codec)
+    #[arg(long, global = true, value_parser = ["avc", "hevc", "av1"])]
+    codec: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Login in
+    ///
+    /// 不支持自动刷新登录信息 (扫码登录不提供refresh_token),
+    /// 登录快过期或需要刷新时会提示, 请重新执行 bili login
     Login {
         /// whether output QR in console,default false
         #[arg(short, long)]
         console: bool,
+
+        /// 显示登录是否有效和过期时间, 不登录
+        #[arg(long)]
+        status: bool,
+
+        /// 使用浏览器导出的cookies.txt文件, 或者 "SESSDATA=...; bili_jct=..." 格式的cookie登录,
+        /// 为 - 时从标准输入读取, 不带值时提示输入 (写在命令行中的cookie会留在shell历史中)
+        #[arg(long, value_name = "FILE", conflicts_with = "sessdata")]
+        cookies: Option<Option<String>>,
+
+        /// 只使用SESSDATA登录, 为 - 时从标准输入读取, 不带值时提示输入
+        /// (写在命令行中的值会留在shell历史中)
+        #[arg(long, value_name = "SESSDATA")]
+        sessdata: Option<Option<String>>,
+    },
+    /// 退出登录并删除保存的登录信息
+    Logout,
+    /// 管理配置
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// 管理本地数据库
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+    /// 管理多个账号
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommands,
     },
     /// print user information
-    User,
+    User {
+        /// 以JSON格式输出
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// 检查ffmpeg并显示支持的功能
+    Ffmpeg {
+        /// 设置ffmpeg路径 (配置项 ffmpeg_path, 环境变量 BILI_FFMPEG_PATH 优先)
+        #[arg(long)]
+        path: Option<String>,
+    },
+
+    /// 交互式浏览、勾选视频并加入下载队列
+    Tui {
+        /// 同时下载的视频数量 (配置项 jobs, 默认2)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+
+    /// 启动本地HTTP/JSON接口, 供其他程序提交和管理下载任务
+    Serve {
+        /// 监听地址, 不是本机地址时需要设置访问令牌 (配置项 serve_token)
+        #[arg(long, default_value = "127.0.0.1:8866")]
+        listen: String,
+
+        /// 同时下载的视频数量 (配置项 jobs, 默认2)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+
+    /// 订阅剧集、合集或视频列表, 同步时只下载新的视频
+    Subscribe {
+        #[command(subcommand)]
+        command: SubscribeCommands,
+    },
+
+    /// 收藏夹
+    Fav {
+        #[command(subcommand)]
+        command: FavCommands,
+    },
+
+    /// 显示av号、BV号、cid、ep、ss的对应关系
+    Id {
+        /// 网址、av号、BV号、ep、ss或md
+        input: String,
+    },
+
+    /// 显示网址会下载哪些视频, 不下载
+    Resolve {
+        #[arg(value_parser = check_download_url)]
+        url: String,
+    },
 
     /// download from url
     Download {
-        /// url to download from bilibili
-        #[arg(value_parser = check_download_url)]
-        url: Option<String>,
+        /// url to download from bilibili, 可以有多个, - 表示从标准输入读取
+        #[arg(value_parser = check_download_input)]
+        urls: Vec<String>,
+
+        /// 从文件读取网址, 每行一个, # 开头的行是注释
+        #[arg(long)]
+        batch_file: Option<String>,
 
         /// 断点续传，必须选择和上次一样的清晰度，否则会出现视频无法使用的情况。
         #[arg(short,long,action = clap::ArgAction::SetTrue)]
         resume: bool,
 
-        ///  使用url解析剧集数据而不是id, 有的剧集下不了加上这个试试 (对集合类视频有效，对BV无效)
+        /// 只使用url解析剧集数据而不是id (默认id解析失败时会自动改用url, 对集合类视频有效，对BV无效)
         #[arg(short,long,action = clap::ArgAction::SetTrue)]
         parse_input_url: bool,
 
         /// 加上这个可以选择要下载的seasons, 而不是全部的seasons
         #[arg(short,long,action = clap::ArgAction::SetTrue)]
         choose_seasons: bool,
+
+        /// 保留合并前的音视频临时文件, 用于排查合并失败的问题
+        #[arg(short,long,action = clap::ArgAction::SetTrue)]
+        keep_temp: bool,
+
+        /// 番剧 : 同时下载PV、OP/ED等花絮, 保存在每季目录下的 Extras
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        include_extras: bool,
+
+        /// 下载稍后再看中的全部视频
+        #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["urls", "batch_file"])]
+        watch_later: bool,
+
+        /// 稍后再看 : 下载并校验成功后从列表中删除
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        remove_after: bool,
+
+        /// UP主投稿 : 只下载该日期及之后发布的视频 (YYYY-MM-DD)
+        #[arg(long, value_parser = parse_date)]
+        since: Option<NaiveDate>,
+
+        /// UP主投稿 : 只下载该日期及之前发布的视频 (YYYY-MM-DD)
+        #[arg(long, value_parser = parse_date)]
+        until: Option<NaiveDate>,
+
+        /// UP主投稿 : 标题关键字
+        #[arg(long)]
+        keyword: Option<String>,
+
+        /// UP主投稿 : 最短时长 (秒)
+        #[arg(long)]
+        min_duration: Option<i64>,
+
+        /// UP主投稿 : 最长时长 (秒)
+        #[arg(long)]
+        max_duration: Option<i64>,
+
+        /// UP主投稿 : 只下载排序后的前N个视频
+        #[arg(long)]
+        latest: Option<usize>,
+
+        /// UP主投稿 : 排序方式, pubdate 最新发布 / click 最多播放 / stow 最多收藏
+        #[arg(long, value_parser = ["pubdate", "click", "stow"])]
+        order: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileCommands {
+    /// 列出已登录的账号
+    List,
+    /// 切换默认使用的账号
+    Use {
+        #[arg(value_parser = check_profile_name)]
+        name: String,
+    },
+    /// 删除账号和它的配置
+    Remove {
+        #[arg(value_parser = check_profile_name)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommands {
+    /// 显示配置项的值和来源
+    Get { key: String },
+    /// 写入配置文件, 指定 --profile 时只写入这个账号的设置, 值为空时删除配置项
+    Set { key: String, value: String },
+    /// 列出全部配置项
+    List,
+    /// 使用编辑器打开配置文件
+    Edit,
+    /// 显示配置文件路径
+    Path,
+    /// 更换加密登录信息的密钥并重新加密
+    Rekey {
+        /// 使用密码生成密钥 (可以通过环境变量BILI_PASSPHRASE提供), 否则生成新的密钥文件
+        #[arg(long)]
+        passphrase: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommands {
+    /// 显示数据库版本和迁移状态
+    Status,
+    /// 执行未执行的迁移
+    Migrate,
+    /// 备份数据库
+    Backup {
+        /// 备份文件的路径, 默认保存到配置目录下的 backups 目录
+        path: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum FavCommands {
+    /// 列出当前账号的收藏夹
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum SubscribeCommands {
+    /// 添加订阅
+    Add {
+        /// 剧集、合集、视频列表或UP主空间的网址
+        #[arg(value_parser = check_download_url)]
+        url: String,
+    },
+    /// 列出订阅
+    List,
+    /// 删除订阅
+    Remove {
+        /// 订阅编号
+        id: i64,
+    },
+    /// 下载订阅中的新视频
+    Sync {
+        /// 持续运行, 每隔指定的分钟数同步一次
+        #[arg(short, long)]
+        watch: Option<u64>,
+
+        /// 同时下载的视频数量 (配置项 jobs, 默认2)
+        #[arg(short, long)]
+        jobs: Option<usize>,
     },
 }
 
-fn check_download_url(s: &str) -> crate::Result<String> {
+pub(crate) fn check_download_url(s: &str) -> crate::Result<String> {
     if !(s.contains("http://") || s.contains("https://")) {
         return Err(anyhow::Error::msg("not valid url"));
     };
     Ok(s.replace("http://", "https://"))
 }
 
+fn parse_date(s: &str) -> crate::Result<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| anyhow::Error::msg("日期格式应为 YYYY-MM-DD"))
+}
+
+/// 账号名只能包含字母、数字、- 和 _
+fn check_profile_name(s: &str) -> crate::Result<String> {
+    if s.is_empty()
+        || !s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow::Error::msg("账号名只能包含字母、数字、- 和 _"));
+    }
+    Ok(s.to_owned())
+}
+
+/// 网址或者 - (从标准输入读取)
+fn check_download_input(s: &str) -> crate::Result<String> {
+    if s == "-" {
+        return Ok(s.to_owned());
+    }
+    check_download_url(s)
+}
+
 pub(crate) async fn run() -> crate::Result<()> {
     CLI.set(Cli::parse()).unwrap();
+    let mut init = config::init();
+
+    // db 命令自己管理迁移, 例如升级前先备份, 账号的设置在迁移之后读取
+    if !matches!(cli().command, Some(Commands::Db { .. })) {
+        migration::migrate_on_startup().await?;
+        if init.is_ok() {
+            init = config::init_profile().await;
+        }
+    }
+    if let Err(err) = init {
+        // 配置有错误时仍然可以用 config 命令修改
+        if !matches!(cli().command, Some(Commands::Config { .. })) {
+            return Err(err);
+        }
+        println!("{}", style(err).yellow());
+    }
+    config::apply_proxy();
 
     match &cli().command {
-        Some(Commands::Login { console }) => {
+        Some(Commands::Login { status: true, .. }) => {
+            user::login_status().await?;
+        }
+        Some(Commands::Login {
+            cookies: Some(cookies),
+            ..
+        }) => {
+            user::login_with_cookies(cookies.as_deref()).await?;
+        }
+        Some(Commands::Login {
+            sessdata: Some(sessdata),
+            ..
+        }) => {
+            user::login_with_sessdata(sessdata.as_deref()).await?;
+        }
+        Some(Commands::Login { console, .. }) => {
             user::login(console).await?;
         }
-        Some(Commands::User) => {
-            user::user_info().await?;
+        Some(Commands::Logout) => {
+            user::logout().await?;
+        }
+        Some(Commands::Config { command }) => match command {
+            ConfigCommands::Get { key } => config::get(key)?,
+            ConfigCommands::Set { key, value } => config::set(key, value).await?,
+            ConfigCommands::List => config::list()?,
+            ConfigCommands::Edit => config::edit()?,
+            ConfigCommands::Path => config::path()?,
+            ConfigCommands::Rekey { passphrase } => secret::rekey(*passphrase).await?,
+        },
+        Some(Commands::Db { command }) => match command {
+            DbCommands::Status => migration::status().await?,
+            DbCommands::Migrate => migration::migrate().await?,
+            DbCommands::Backup { path } => migration::backup(path).await?,
+        },
+        Some(Commands::Profile { command }) => match command {
+            ProfileCommands::List => profile::list().await?,
+            ProfileCommands::Use { name } => profile::use_profile(name).await?,
+            ProfileCommands::Remove { name } => profile::remove(name).await?,
+        },
+        Some(Commands::User { json }) => {
+            user::user_info(*json).await?;
+        }
+        Some(Commands::Ffmpeg { path }) => {
+            ffmpeg::ffmpeg_report(path).await?;
+        }
+        Some(Commands::Tui { .. }) => {
+            ffmpeg::ffmpeg_check().await?;
+            config::enter_output_dir()?;
+            tui::run(config::jobs()).await?;
+        }
+        Some(Commands::Serve { listen, .. }) => {
+            ffmpeg::ffmpeg_check().await?;
+            config::enter_output_dir()?;
+            serve::run(listen, config::jobs()).await?;
+        }
+        Some(Commands::Subscribe { command }) => match command {
+            SubscribeCommands::Add { url } => subscribe::add(url).await?,
+            SubscribeCommands::List => subscribe::list().await?,
+            SubscribeCommands::Remove { id } => subscribe::remove(*id).await?,
+            SubscribeCommands::Sync { watch, .. } => {
+                ffmpeg::ffmpeg_check().await?;
+                config::enter_output_dir()?;
+                subscribe::sync(*watch, config::jobs()).await?;
+            }
+        },
+        Some(Commands::Fav { command }) => match command {
+            FavCommands::List => fav::list().await?,
+        },
+        Some(Commands::Resolve { url }) => {
+            resolver::print_resolve(url.to_owned()).await?;
+        }
+        Some(Commands::Id { input }) => {
+            id::print_ids(input).await?;
+        }
+        Some(Commands::Download {
+            watch_later: true, ..
+        }) => {
+            ffmpeg::ffmpeg_check().await?;
+            config::enter_output_dir()?;
+            resolver::download(resolver::WATCH_LATER_URL.to_owned(), true).await?;
         }
         Some(Commands::Download {
-            url,
-            resume: _,
-            parse_input_url: _,
-            choose_seasons: _,
+            urls, batch_file, ..
         }) => {
-            let url = if let Some(url) = url {
-                url.to_string()
+            ffmpeg::ffmpeg_check().await?;
+            if urls.len() == 1 && urls[0] != "-" && batch_file.is_none() {
+                config::enter_output_dir()?;
+                resolver::download(urls[0].clone(), true).await?;
+            } else if !urls.is_empty() || batch_file.is_some() {
+                // 先读取网址, batch_file 是相对于原来目录的路径
+                let inputs = batch::read_inputs(urls, batch_file)?;
+                config::enter_output_dir()?;
+                batch::download_all(inputs).await?;
             } else {
-                check_download_url(
+                let url = check_download_url(
                     Input::<String>::new()
                         .with_prompt("请输入视频网址")
                         .interact_text()?
                         .as_str(),
-                )?
-            };
-            download::download(url).await?;
+                )?;
+                config::enter_output_dir()?;
+                resolver::download(url, true).await?;
+            }
         }
         None => {
             let mut factory = Cli::command();
@@ -88,14 +456,50 @@ pub(crate) async fn run() -> crate::Result<()> {
 fn cli() -> &'static Cli {
     CLI.get().unwrap()
 }
+/// 是否以JSON输出结果
+pub(crate) fn json_output_value() -> bool {
+    matches!(
+        CLI.get().map(|x| &x.command),
+        Some(Some(Commands::User { json: true }))
+    )
+}
+
+/// 输出提示信息, 以JSON输出结果时写到stderr, 保持stdout只有JSON
+pub(crate) fn print_notice(message: impl std::fmt::Display) {
+    if json_output_value() {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+pub(crate) fn profile_value() -> Option<String> {
+    cli().profile.clone()
+}
+
+/// 命令行参数中的配置项, 优先于环境变量和配置文件
+pub(crate) fn config_flag_value(key: &str) -> Option<String> {
+    let cli = CLI.get()?;
+    match key {
+        "output_dir" => cli.output_dir.clone(),
+        "quality" => cli.quality.map(|x| x.to_string()),
+        "rate_limit" => cli.rate_limit.map(|x| x.to_string()),
+        "proxy" => cli.proxy.clone(),
+        "codec" => cli.codec.clone(),
+        "jobs" => match &cli.command {
+            Some(Commands::Tui { jobs })
+            | Some(Commands::Serve { jobs, .. })
+            | Some(Commands::Subscribe {
+                command: SubscribeCommands::Sync { jobs, .. },
+            }) => jobs.map(|x| x.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 pub(crate) fn resume_download_value() -> bool {
-    if let Some(Commands::Download {
-        url: _,
-        resume,
-        parse_input_url: _,
-        choose_seasons: _,
-    }) = cli().command
-    {
+    if let Some(Commands::Download { resume, .. }) = cli().command {
         return resume;
     }
     false
@@ -103,10 +507,7 @@ pub(crate) fn resume_download_value() -> bool {
 
 pub(crate) fn parse_input_url_value() -> bool {
     if let Some(Commands::Download {
-        url: _,
-        resume: _,
-        parse_input_url,
-        choose_seasons: _,
+        parse_input_url, ..
     }) = cli().command
     {
         return parse_input_url;
@@ -115,14 +516,54 @@ pub(crate) fn parse_input_url_value() -> bool {
 }
 
 pub(crate) fn choose_seasons_value() -> bool {
-    if let Some(Commands::Download {
-        url: _,
-        resume: _,
-        parse_input_url: _,
-        choose_seasons,
-    }) = cli().command
-    {
+    if let Some(Commands::Download { choose_seasons, .. }) = cli().command {
         return choose_seasons;
     }
     false
 }
+
+pub(crate) fn keep_temp_value() -> bool {
+    if let Some(Commands::Download { keep_temp, .. }) = cli().command {
+        return keep_temp;
+    }
+    false
+}
+
+pub(crate) fn include_extras_value() -> bool {
+    if let Some(Commands::Download { include_extras, .. }) = cli().command {
+        return include_extras;
+    }
+    false
+}
+
+pub(crate) fn remove_after_value() -> bool {
+    if let Some(Commands::Download { remove_after, .. }) = cli().command {
+        return remove_after;
+    }
+    false
+}
+
+pub(crate) fn space_filter_value() -> download::SpaceFilter {
+    if let Some(Commands::Download {
+        since,
+        until,
+        keyword,
+        min_duration,
+        max_duration,
+        latest,
+        order,
+        ..
+    }) = &cli().command
+    {
+        return download::SpaceFilter {
+            since: *since,
+            until: *until,
+            keyword: keyword.clone(),
+            min_duration: *min_duration,
+            max_duration: *max_duration,
+            latest: *latest,
+            order: order.clone(),
+        };
+    }
+    download::SpaceFilter::default()
+}
@@ -0,0 +1,562 @@
+use std::path::Path;
+use std::process::Command;
+
+use console::{style, Emoji};
+use once_cell::sync::OnceCell;
+use toml_edit::Document;
+
+use crate::{cli, local, profile};
+
+/// 配置项的值类型
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Number,
+    /// 文件名模板, 至少包含一个变量
+    Template,
+    /// 只能是列出的值之一
+    Choice(&'static [&'static str]),
+}
+
+struct Key {
+    name: &'static str,
+    kind: Kind,
+    description: &'static str,
+}
+
+/// 配置文件支持的配置项, 环境变量为 BILI_ 加大写的名称
+static KEYS: &[Key] = &[
+    Key {
+        name: "output_dir",
+        kind: Kind::Text,
+        description: "下载保存的目录, 默认为当前目录",
+    },
+    Key {
+        name: "quality",
+        kind: Kind::Number,
+        description: "默认清晰度, 例如 80 为1080P, 没有时下载较低的清晰度",
+    },
+    Key {
+        name: "jobs",
+        kind: Kind::Number,
+        description: "同时下载的视频数量, 默认2",
+    },
+    Key {
+        name: "rate_limit",
+        kind: Kind::Number,
+        description: "每个文件的下载限速 (KB/s)",
+    },
+    Key {
+        name: "proxy",
+        kind: Kind::Text,
+        description: "代理地址, 例如 http://127.0.0.1:7890",
+    },
+    Key {
+        name: "ffmpeg_path",
+        kind: Kind::Text,
+        description: "ffmpeg路径",
+    },
+    Key {
+        name: "audio_language",
+        kind: Kind::Text,
+        description: "写入音频流的语言 (ISO 639-2), 例如 chi / jpn, 不设置时不写入",
+    },
+    Key {
+        name: "codec",
+        kind: Kind::Choice(&["avc", "hevc", "av1"]),
+        description: "优先下载的视频编码 (avc/hevc/av1), 没有时下载同一清晰度的其他编码",
+    },
+    Key {
+        name: "serve_token",
+        kind: Kind::Text,
+        description: "serve 接口的访问令牌, 请求需要带上 Authorization: Bearer <令牌>",
+    },
+    Key {
+        name: "name_template",
+        kind: Kind::Template,
+        description: "文件名模板, 可以使用 {title} 和 {bvid}, 默认 {title}",
+    },
+];
+
+/// 旧版本使用的环境变量, 优先级低于新的名称 : (配置项, 环境变量)
+static DEPRECATED_ENV: &[(&str, &str)] = &[("ffmpeg_path", "BILI_FFMPEG")];
+
+/// 视频编码对应的codecid
+const CODECS: [(&str, i64); 3] = [("avc", 7), ("hevc", 12), ("av1", 13)];
+
+/// 文件名模板中的变量
+const TEMPLATE_VARIABLES: [&str; 2] = ["{title}", "{bvid}"];
+
+const DEFAULT_JOBS: usize = 2;
+
+/// 配置项的来源
+enum Source {
+    Flag,
+    Env(String),
+    Profile(String),
+    File,
+}
+
+impl Source {
+    fn describe(&self) -> String {
+        match self {
+            Source::Flag => "命令行参数".to_owned(),
+            Source::Env(name) => format!("环境变量 {}", name),
+            Source::Profile(name) => format!("账号 {} 的设置", name),
+            Source::File => "配置文件".to_owned(),
+        }
+    }
+}
+
+static FILE: OnceCell<Document> = OnceCell::new();
+/// 当前账号的偏好设置 : (账号, 配置项)
+static PROFILE: OnceCell<(String, Vec<(String, String)>)> = OnceCell::new();
+
+/// 配置文件路径
+pub(crate) fn config_path() -> String {
+    local::join_paths(vec![local::cfg_local_dir().as_str(), "config.toml"])
+}
+
+fn read_file() -> crate::Result<Document> {
+    let path = config_path();
+    if !Path::new(&path).exists() {
+        return Ok(Document::new());
+    }
+    std::fs::read_to_string(&path)?
+        .parse::<Document>()
+        .map_err(|err| anyhow::Error::msg(format!("配置文件格式错误 ({}) : {}", path, err)))
+}
+
+fn write_file(doc: &Document) -> crate::Result<()> {
+    std::fs::create_dir_all(local::cfg_local_dir())?;
+    let path = config_path();
+    std::fs::write(&path, doc.to_string())?;
+    local::restrict_permissions(&path, 0o600)?;
+    Ok(())
+}
+
+fn find_key(name: &str) -> crate::Result<&'static Key> {
+    KEYS.iter().find(|x| x.name == name).ok_or_else(|| {
+        anyhow::Error::msg(format!(
+            "没有这个配置项 : {}, 可用的配置项 : {}",
+            name,
+            KEYS.iter().map(|x| x.name).collect::<Vec<_>>().join(", ")
+        ))
+    })
+}
+
+fn env_name(key: &Key) -> String {
+    format!("BILI_{}", key.name.to_uppercase())
+}
+
+fn check_value(key: &Key, value: &str) -> crate::Result<()> {
+    if key.kind == Kind::Number && value.parse::<u64>().is_err() {
+        return Err(anyhow::Error::msg(format!(
+            "{} 应为非负整数 : {}",
+            key.name, value
+        )));
+    }
+    if let Kind::Choice(choices) = key.kind {
+        if !choices.contains(&value) {
+            return Err(anyhow::Error::msg(format!(
+                "{} 应为 {} 之一 : {}",
+                key.name,
+                choices.join(" / "),
+                value
+            )));
+        }
+    }
+    if key.kind == Kind::Template && !TEMPLATE_VARIABLES.iter().any(|x| value.contains(x)) {
+        return Err(anyhow::Error::msg(format!(
+            "{} 应包含 {} : {}",
+            key.name,
+            TEMPLATE_VARIABLES.join(" 或 "),
+            value
+        )));
+    }
+    Ok(())
+}
+
+fn file_value(doc: &Document, name: &str) -> Option<String> {
+    let item = doc.get(name)?;
+    match item.as_str() {
+        Some(text) => Some(text.to_owned()),
+        None => item.as_value().map(|x| x.to_string().trim().to_owned()),
+    }
+}
+
+/// 读取配置项 : 命令行参数 > 环境变量 > 账号的设置 > 配置文件
+fn lookup(key: &Key) -> Option<(String, Source)> {
+    lookup_from(
+        key,
+        cli::config_flag_value(key.name),
+        |name| std::env::var(name).ok(),
+        PROFILE.get(),
+        FILE.get(),
+    )
+}
+
+fn lookup_from(
+    key: &Key,
+    flag: Option<String>,
+    env: impl Fn(&str) -> Option<String>,
+    profile: Option<&(String, Vec<(String, String)>)>,
+    file: Option<&Document>,
+) -> Option<(String, Source)> {
+    if let Some(value) = flag {
+        return Some((value, Source::Flag));
+    }
+    if let Some(value) = env(&env_name(key)).filter(|x| !x.is_empty()) {
+        return Some((value, Source::Env(env_name(key))));
+    }
+    for (_, name) in DEPRECATED_ENV.iter().filter(|(name, _)| *name == key.name) {
+        if let Some(value) = env(name).filter(|x| !x.is_empty()) {
+            let describe = format!("{} (已弃用, 请使用 {})", name, env_name(key));
+            return Some((value, Source::Env(describe)));
+        }
+    }
+    if let Some((profile, prefs)) = profile {
+        if let Some((_, value)) = prefs.iter().find(|(name, _)| name == key.name) {
+            return Some((value.clone(), Source::Profile(profile.clone())));
+        }
+    }
+    file_value(file?, key.name).map(|x| (x, Source::File))
+}
+
+/// 读取配置文件并检查全部配置项, 设置代理, 在执行命令前调用
+pub(crate) fn init() -> crate::Result<()> {
+    let doc = read_file()?;
+    for (name, _) in doc.iter() {
+        if find_key(name).is_err() {
+            cli::print_notice(style(format!("配置文件中有未知的配置项 : {}", name)).yellow());
+        }
+    }
+    let _ = FILE.set(doc);
+    check_all()
+}
+
+/// 读取当前账号的偏好设置, 需要在数据库迁移之后调用
+pub(crate) async fn init_profile() -> crate::Result<()> {
+    let profile = profile::current().await?;
+    let prefs = profile::load_prefs(&profile).await?;
+    for (name, _) in &prefs {
+        if find_key(name).is_err() {
+            cli::print_notice(
+                style(format!("账号 {} 中有未知的配置项 : {}", profile, name)).yellow(),
+            );
+        }
+    }
+    let _ = PROFILE.set((profile, prefs));
+    check_all()
+}
+
+fn check_all() -> crate::Result<()> {
+    for key in KEYS {
+        if let Some((value, source)) = lookup(key) {
+            check_value(key, &value)
+                .map_err(|err| anyhow::Error::msg(format!("{} ({})", err, source.describe())))?;
+        }
+    }
+    Ok(())
+}
+
+/// 设置代理, 在读取全部配置之后调用
+pub(crate) fn apply_proxy() {
+    if let Some(proxy) = proxy() {
+        // reqwest 读取这两个环境变量作为代理
+        std::env::set_var("HTTP_PROXY", &proxy);
+        std::env::set_var("HTTPS_PROXY", &proxy);
+    }
+}
+
+fn text(name: &str) -> Option<String> {
+    lookup(find_key(name).ok()?).map(|x| x.0)
+}
+
+fn number(name: &str) -> Option<u64> {
+    text(name).and_then(|x| x.parse().ok())
+}
+
+pub(crate) fn output_dir() -> Option<String> {
+    text("output_dir")
+}
+
+pub(crate) fn quality() -> Option<i64> {
+    number("quality").map(|x| x as i64)
+}
+
+pub(crate) fn jobs() -> usize {
+    number("jobs")
+        .map(|x| x as usize)
+        .filter(|x| *x > 0)
+        .unwrap_or(DEFAULT_JOBS)
+}
+
+/// 每秒最多下载的字节数
+pub(crate) fn rate_limit() -> Option<u64> {
+    number("rate_limit").filter(|x| *x > 0).map(|x| x * 1024)
+}
+
+pub(crate) fn proxy() -> Option<String> {
+    text("proxy")
+}
+
+pub(crate) fn serve_token() -> Option<String> {
+    text("serve_token").filter(|x| !x.is_empty())
+}
+
+pub(crate) fn audio_language() -> Option<String> {
+    text("audio_language")
+}
+
+pub(crate) fn ffmpeg_path() -> Option<String> {
+    text("ffmpeg_path")
+}
+
+/// 优先下载的视频编码的codecid
+pub(crate) fn codec() -> Option<i64> {
+    let name = text("codec")?;
+    CODECS.iter().find(|(x, _)| *x == name).map(|(_, id)| *id)
+}
+
+/// 按 name_template 生成文件名, 没有设置时为标题
+pub(crate) fn render_name_template(title: &str, bvid: &str) -> String {
+    match text("name_template") {
+        Some(template) => template.replace("{title}", title).replace("{bvid}", bvid),
+        None => title.to_owned(),
+    }
+}
+
+/// 切换到下载目录, 目录不存在时创建
+pub(crate) fn enter_output_dir() -> crate::Result<()> {
+    if let Some(dir) = output_dir() {
+        std::fs::create_dir_all(&dir)?;
+        std::env::set_current_dir(&dir)
+            .map_err(|err| anyhow::Error::msg(format!("无法进入下载目录 {} : {}", dir, err)))?;
+    }
+    Ok(())
+}
+
+/// 显示配置项的值和来源
+pub(crate) fn get(name: &str) -> crate::Result<()> {
+    let key = find_key(name)?;
+    match lookup(key) {
+        Some((value, source)) => println!("{} ({})", value, style(source.describe()).dim()),
+        None => println!("{}", style("未设置").dim()),
+    }
+    Ok(())
+}
+
+/// 写入配置文件, 值为空时删除配置项
+pub(crate) fn set_value(name: &str, value: &str) -> crate::Result<()> {
+    let key = find_key(name)?;
+    let mut doc = read_file()?;
+    if value.is_empty() {
+        doc.remove(name);
+    } else {
+        check_value(key, value)?;
+        doc[name] = match key.kind {
+            Kind::Text | Kind::Template | Kind::Choice(_) => toml_edit::value(value),
+            Kind::Number => toml_edit::value(value.parse::<i64>()?),
+        };
+    }
+    write_file(&doc)
+}
+
+/// 写入配置文件, 指定 --profile 时写入这个账号的设置
+pub(crate) async fn set(name: &str, value: &str) -> crate::Result<()> {
+    match cli::profile_value() {
+        Some(profile) => {
+            let key = find_key(name)?;
+            if !value.is_empty() {
+                check_value(key, value)?;
+            }
+            profile::save_pref(&profile, name, value).await?;
+        }
+        None => set_value(name, value)?,
+    }
+    if value.is_empty() {
+        println!("{}已删除配置项 : {}", Emoji("✨", ""), name);
+    } else {
+        println!("{}{} = {}", Emoji("✨", ""), name, value);
+    }
+    let key = find_key(name)?;
+    if let Some((_, source)) = lookup(key) {
+        let overridden = match source {
+            Source::Flag | Source::Env(_) => true,
+            Source::Profile(_) => cli::profile_value().is_none(),
+            Source::File => false,
+        };
+        if overridden {
+            println!(
+                "{}",
+                style(format!("当前使用{}中的值", source.describe())).yellow()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// 列出全部配置项的当前值和来源
+pub(crate) fn list() -> crate::Result<()> {
+    for key in KEYS {
+        match lookup(key) {
+            Some((value, source)) => println!(
+                "{} = {} ({})",
+                style(key.name).bold(),
+                value,
+                style(source.describe()).dim()
+            ),
+            None => println!(
+                "{} {}",
+                style(key.name).bold(),
+                style(format!("# {}", key.description)).dim()
+            ),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn path() -> crate::Result<()> {
+    println!("{}", config_path());
+    Ok(())
+}
+
+/// 配置文件不存在时生成的内容, 全部配置项都被注释
+fn template() -> String {
+    let mut content =
+        "# bili 配置文件, 优先级 : 配置文件 < 环境变量 (BILI_ 加大写的名称) < 命令行参数\n"
+            .to_owned();
+    for key in KEYS {
+        let example = match key.kind {
+            Kind::Text => "\"\"",
+            Kind::Number => "0",
+            Kind::Template => "\"{title}\"",
+            Kind::Choice(_) => "\"\"",
+        };
+        content.push_str(&format!(
+            "\n# {}\n# {} = {}\n",
+            key.description, key.name, example
+        ));
+    }
+    content
+}
+
+/// 使用 VISUAL 或 EDITOR 中的编辑器打开配置文件, 保存后检查格式
+pub(crate) fn edit() -> crate::Result<()> {
+    let path = config_path();
+    if !Path::new(&path).exists() {
+        std::fs::create_dir_all(local::cfg_local_dir())?;
+        std::fs::write(&path, template())?;
+        local::restrict_permissions(&path, 0o600)?;
+    }
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if cfg!(target_os = "windows") {
+                "notepad".to_owned()
+            } else {
+                "vi".to_owned()
+            }
+        });
+    let status = Command::new(&editor)
+        .arg(&path)
+        .status()
+        .map_err(|err| anyhow::Error::msg(format!("无法启动编辑器 {} : {}", editor, err)))?;
+    if !status.success() {
+        return Err(anyhow::Error::msg(format!("编辑器异常退出 : {}", status)));
+    }
+    let doc = read_file()?;
+    for (name, _) in doc.iter() {
+        let key = find_key(name)?;
+        if let Some(value) = file_value(&doc, name) {
+            check_value(key, &value)?;
+        }
+    }
+    println!("{}配置文件已保存", Emoji("✨", ""));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> &'static Key {
+        find_key(name).unwrap()
+    }
+
+    fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    fn lookup_value(
+        flag: Option<&str>,
+        vars: &[(&str, &str)],
+        profile: Option<&(String, Vec<(String, String)>)>,
+        file: Option<&Document>,
+    ) -> Option<(String, String)> {
+        lookup_from(
+            key("quality"),
+            flag.map(|x| x.to_owned()),
+            env(vars),
+            profile,
+            file,
+        )
+        .map(|(value, source)| (value, source.describe()))
+    }
+
+    #[test]
+    fn lookup_precedence() {
+        let file = "quality = 16".parse::<Document>().unwrap();
+        let profile = (
+            "alt".to_owned(),
+            vec![("quality".to_owned(), "32".to_owned())],
+        );
+        let vars = [("BILI_QUALITY", "64")];
+        assert_eq!(
+            lookup_value(Some("80"), &vars, Some(&profile), Some(&file)),
+            Some(("80".to_owned(), "命令行参数".to_owned()))
+        );
+        assert_eq!(
+            lookup_value(None, &vars, Some(&profile), Some(&file)),
+            Some(("64".to_owned(), "环境变量 BILI_QUALITY".to_owned()))
+        );
+        assert_eq!(
+            lookup_value(None, &[("BILI_QUALITY", "")], Some(&profile), Some(&file)),
+            Some(("32".to_owned(), "账号 alt 的设置".to_owned()))
+        );
+        assert_eq!(
+            lookup_value(None, &[], None, Some(&file)),
+            Some(("16".to_owned(), "配置文件".to_owned()))
+        );
+        assert_eq!(lookup_value(None, &[], None, None), None);
+    }
+
+    #[test]
+    fn deprecated_env_name() {
+        let lookup = |vars: &[(&str, &str)]| {
+            lookup_from(key("ffmpeg_path"), None, env(vars), None, None).map(|x| x.0)
+        };
+        assert_eq!(lookup(&[("BILI_FFMPEG", "/old")]), Some("/old".to_owned()));
+        assert_eq!(
+            lookup(&[("BILI_FFMPEG", "/old"), ("BILI_FFMPEG_PATH", "/new")]),
+            Some("/new".to_owned())
+        );
+    }
+
+    #[test]
+    fn check_codec() {
+        assert!(check_value(key("codec"), "hevc").is_ok());
+        assert!(check_value(key("codec"), "h265").is_err());
+    }
+
+    #[test]
+    fn check_name_template() {
+        assert!(check_value(key("name_template"), "{title} [{bvid}]").is_ok());
+        assert!(check_value(key("name_template"), "video").is_err());
+    }
+}
@@ -1,253 +1,425 @@
-use crate::{cli, ffmpeg, local, user};
+use crate::queue::{Job, JobState, VideoFormat};
+use crate::{api, cli, config, ffmpeg, local, queue, user};
 use anyhow::{Context, Ok};
-use bilirust::{Audio, Ss, SsState, Video, FNVAL_DASH, FNVAL_MP4, VIDEO_QUALITY_4K};
-use console::Emoji;
+use bilirust::{SsState, FNVAL_DASH, VIDEO_QUALITY_4K};
+use chrono::{Local, NaiveDate, TimeZone};
+use console::{style, Emoji};
 use dialoguer::Select;
 use futures::stream::TryStreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressBar;
 use itertools::Itertools;
-use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio_util::io::StreamReader;
 
-lazy_static! {
-    static ref SHORT_PATTERN: regex::Regex =
-        regex::Regex::new(r"//b\d+\.tv/([0-9a-zA-Z]+)$").unwrap();
-    static ref BV_PATTERN: regex::Regex = regex::Regex::new(r"BV[0-9a-zA-Z]{10}").unwrap();
-    static ref SERIES_PATTERN: regex::Regex = regex::Regex::new(r"((ep)|(ss))[0-9]+").unwrap();
-    static ref USER_COLLECTION_DETAIL_PATTERN: regex::Regex =
-        regex::Regex::new(r"/([0-9]+)/channel/collectiondetail\?sid=([0-9]+)").unwrap();
-}
-
-pub(crate) async fn download(url: String) -> crate::Result<()> {
-    let mut url = url;
-    //解析短链接并重定向
-    if let Some(_) = SHORT_PATTERN.find(url.as_str()) {
-        let rsp = reqwest::ClientBuilder::new()
-            .redirect(reqwest::redirect::Policy::none())
-            .build()?
-            .get(&url)
-            .send()
-            .await?;
-        match rsp.status().as_u16() {
-            302 => {
-                let headers = rsp.headers();
-                let location = headers.get("location");
-                if let Some(location) = location {
-                    url = location.to_str()?.to_owned();
-                }
-            }
-            _ => return Err(anyhow::Error::msg("resolve short links error")),
-        }
-    }
-    //下载bv链接
-    if let Some(find) = BV_PATTERN.find(url.as_str()) {
-        return download_bv((&url[find.start()..find.end()]).to_string()).await;
+/// 番剧花絮的保存目录
+const EXTRAS_FOLDER: &str = "Extras";
+
+/// 稍后再看的保存目录
+const WATCH_LATER_TITLE: &str = "稍后再看";
+
+/// 每次请求的投稿数量
+const SPACE_PAGE_SIZE: i64 = 30;
+
+/// 可以加入下载队列的视频
+#[derive(Clone, Debug)]
+pub(crate) struct DownloadItem {
+    pub(crate) bvid: String,
+    /// 合集列表中没有cid, 下载时再通过bv获取
+    pub(crate) cid: Option<i64>,
+    /// 显示的标题
+    pub(crate) title: String,
+    /// 保存目录
+    pub(crate) folder: PathBuf,
+    /// 不含扩展名的文件名
+    pub(crate) name: String,
+}
+
+impl DownloadItem {
+    pub(crate) fn mix_file(&self) -> PathBuf {
+        self.folder.join(format!("{}.mp4", self.name))
     }
-    //下载系列 动漫 视频
-    if let Some(find) = SERIES_PATTERN.find(url.as_str()) {
-        return download_series((&(url[find.start()..find.end()])).to_owned(), url).await;
+}
+
+/// 合并中的临时文件, 保留扩展名让封装器识别格式
+pub(crate) fn merging_file(mix_file: &Path) -> PathBuf {
+    let stem = mix_file.file_stem().unwrap_or_default().to_string_lossy();
+    mix_file.with_file_name(format!("{}.merging.mp4", stem))
+}
+
+/// 按配置项 name_template 生成不含扩展名的文件名
+pub(crate) fn item_name(title: &str, bvid: &str) -> String {
+    local::allowed_file_name(&config::render_name_template(title, bvid))
+}
+
+/// 同一来源的一组视频, 例如番剧的一季或一个合集
+pub(crate) struct ItemGroup {
+    pub(crate) title: String,
+    pub(crate) items: Vec<DownloadItem>,
+    /// 无法下载而跳过的视频及原因
+    pub(crate) skipped: Vec<(String, String)>,
+    /// 番剧的解析方式 : id 或 网址
+    pub(crate) resolved_by: Option<&'static str>,
+    /// 番剧的花絮, 下载前可以选择
+    pub(crate) extra: bool,
+}
+
+/// 打印跳过的视频
+pub(crate) fn print_skipped(group: &ItemGroup) {
+    if group.skipped.is_empty() {
+        return;
     }
-    //下载用户的合集
-    if let Some(find) = USER_COLLECTION_DETAIL_PATTERN.captures(url.as_str()) {
-        let mid: i64 = find.get(1).unwrap().as_str().parse().unwrap();
-        let sid: i64 = find.get(2).unwrap().as_str().parse().unwrap();
-        return download_collection_detail(mid, sid).await;
+    println!("  跳过 : {} 个视频", group.skipped.len());
+    for (title, reason) in &group.skipped {
+        println!("    {} : {}", title, reason);
     }
-    Ok(())
 }
 
-async fn download_series(id: String, url: String) -> crate::Result<()> {
-    let client = user::login_client().await?;
+/// 稍后再看中的全部视频
+pub(crate) async fn watch_later_items() -> crate::Result<ItemGroup> {
+    Ok(ItemGroup {
+        title: WATCH_LATER_TITLE.to_owned(),
+        items: api::watch_later()
+            .await?
+            .into_iter()
+            .map(|x| DownloadItem {
+                name: item_name(&x.title, &x.bvid),
+                bvid: x.bvid,
+                cid: None,
+                title: x.title,
+                folder: PathBuf::from(WATCH_LATER_TITLE),
+            })
+            .collect(),
+        skipped: vec![],
+        resolved_by: None,
+        extra: false,
+    })
+}
 
+/// 下载完成并且文件校验通过的视频从稍后再看中删除, 校验失败的保留
+pub(crate) async fn remove_watched(jobs: &[Arc<Job>]) -> crate::Result<()> {
+    let list = api::watch_later().await?;
     println!();
-    println!("{}匹配到合集 : {}", Emoji("✨", ""), id);
-
-    let ss_state = if cli::parse_input_url_value() {
-        client.videos_info_by_url(url).await?
-    } else {
-        client.videos_info(id.clone()).await?
-    };
+    for job in jobs {
+        if !matches!(job.state(), JobState::Done | JobState::Exists) {
+            continue;
+        }
+        let aid = match list.iter().find(|x| x.bvid == job.item.bvid) {
+            Some(x) => x.aid,
+            None => continue,
+        };
+        match ffmpeg::verify_media(&job.item.mix_file()).await {
+            Result::Ok(_) => {
+                api::watch_later_remove(aid).await?;
+                println!("已从稍后再看中删除：{}", job.item.title);
+            }
+            Err(err) => println!(
+                "文件校验失败, 保留在稍后再看中：{} ({})",
+                job.item.title, err
+            ),
+        }
+    }
+    Ok(())
+}
 
-    println!("  系列名称 : {}", ss_state.media_info.series);
-    println!(
-        "  包含番剧 : {} ",
-        ss_state
-            .ss_list
-            .iter()
-            .map(|i| i.title.as_str())
-            .join(" / ")
-    );
+/// 单个视频
+pub(crate) async fn bv_items(client: &bilirust::Client, bv: String) -> crate::Result<ItemGroup> {
+    let bv_info = client.bv_info(bv).await?;
+    Ok(ItemGroup {
+        title: bv_info.title.clone(),
+        items: vec![DownloadItem {
+            name: item_name(&bv_info.title, &bv_info.bvid),
+            bvid: bv_info.bvid,
+            cid: Some(bv_info.cid),
+            title: bv_info.title,
+            folder: PathBuf::new(),
+        }],
+        skipped: vec![],
+        resolved_by: None,
+        extra: false,
+    })
+}
 
+/// 番剧的全部季, 每季一组
+pub(crate) async fn series_items(
+    client: &bilirust::Client,
+    id: String,
+    url: String,
+) -> crate::Result<Vec<ItemGroup>> {
+    let (ss_state, _) = series_state(client, &id, &url).await?;
     let folder = PathBuf::from(local::allowed_file_name(
         ss_state.media_info.series.as_str(),
     ));
-
-    println!("  保存位置 : {}", folder.to_str().unwrap());
-
-    tokio::fs::create_dir_all(folder.as_path()).await?;
-
-    //获得下载的合集id
-    let fetch_ids = if cli::choose_seasons_value() {
-        let titles: Vec<String> = ss_state
-            .ss_list
-            .iter()
-            .map(|x| format!("{} ({})", x.id, x.title.as_str()))
-            .collect();
-        let default_selects = vec![true; titles.len()];
-
-        let selects = dialoguer::MultiSelect::new()
-            .with_prompt("请选择要下载的合集")
-            .items(&titles)
-            .defaults(&default_selects)
-            .interact()
-            .unwrap();
-
-        let mut id_list: Vec<i64> = vec![];
-
-        for i in 0..titles.len() {
-            if selects.contains(&i) {
-                id_list.push(ss_state.ss_list[i].id);
-            }
-        }
-        id_list
-    } else {
-        ss_state.ss_list.iter().map(|x| x.id).collect()
-    };
-
-    // 找到所有的ss
-    // 找到所有ss的bv
-    println!();
-    println!("搜索视频");
-    let mut sss: Vec<(Ss, SsState, String)> = vec![];
+    let vip = user::is_vip().await;
+    let mut groups = vec![];
     for x in ss_state.ss_list {
-        if !fetch_ids.contains(&x.id) {
-            continue;
-        }
-        let videos_info = client.videos_info(format!("ss{}", x.id)).await.unwrap();
+        let (videos_info, strategy) = match season_state(client, x.id).await {
+            Result::Ok(result) => result,
+            Err(err) => {
+                groups.push(ItemGroup {
+                    title: format!("{} ({})", x.id, x.title),
+                    items: vec![],
+                    skipped: vec![(x.title, err.to_string())],
+                    resolved_by: None,
+                    extra: false,
+                });
+                continue;
+            }
+        };
+        let season = fetch_season(x.id).await;
+        let restrictions = season_restrictions(&season, vip);
         let x_dir_name = format!(
             "{} ({}) {}",
             x.id,
             x.title.as_str(),
             videos_info.media_info.season_title.as_str(),
         );
-        println!(
-            "  {} : 共 {} 个视频",
-            x_dir_name.as_str(),
-            videos_info.ep_list.len()
-        );
-        sss.push((x, videos_info, x_dir_name));
-    }
-    println!();
-    println!("下载视频");
-    for x in &sss {
-        let ss_folder = folder.join(x.2.as_str());
-        std::fs::create_dir_all(ss_folder.as_path()).unwrap();
-
-        for ep in &x.1.ep_list {
-            let name = format!("{}. ({}) {}", ep.i, ep.title_format, ep.long_title);
-            let name = local::allowed_file_name(&name);
-            println!();
-            println!("{}", name);
-            let audio_file = ss_folder.join(format!("{}.audio", name));
-            let video_file = ss_folder.join(format!("{}.video", name));
-            let mix_file = ss_folder.join(format!("{}.mp4", name));
-            if mix_file.exists() {
-                println!("已存在：{}", name);
+        let mut items = vec![];
+        let mut skipped = vec![];
+        for ep in &videos_info.ep_list {
+            let title = format!("{}. ({}) {}", ep.i, ep.title_format, ep.long_title);
+            if let Some(reason) = restriction_of(&restrictions, ep.cid) {
+                skipped.push((title, reason));
                 continue;
             }
-            let media_url = client
-                .bv_download_url(
-                    ep.bvid.clone(),
-                    ep.cid.clone(),
-                    FNVAL_DASH,
-                    VIDEO_QUALITY_4K,
-                )
-                .await?;
-            let audio_url = media_url.dash.audio.first().unwrap().base_url.as_str();
-            let video_url = media_url.dash.video.first().unwrap().base_url.as_str();
-            //下载
-            down_file_to(video_url, &video_file, "下载视频").await;
-            println!("{}下载视频完成", Emoji("🚚 ", ""));
-            down_file_to(audio_url, &audio_file, "下载音频").await;
-            println!("{}下载音频完成", Emoji("🚚 ", ""));
-
-            println!("开始合并视频：{}", format!("{}.mp4", name));
-            ffmpeg::ffmpeg_merge_file(
-                vec![video_file.to_str().unwrap(), audio_file.to_str().unwrap()],
-                mix_file.to_str().unwrap(),
-            )
-            .unwrap();
-            println!("{}合并视频完成", Emoji("✨", ""));
-            let _ = std::fs::remove_file(&audio_file);
-            let _ = std::fs::remove_file(&video_file);
-            println!("{}完成数据清理", Emoji("🚚 ", ""));
+            items.push(DownloadItem {
+                name: item_name(&title, &ep.bvid),
+                bvid: ep.bvid.clone(),
+                cid: Some(ep.cid),
+                title,
+                folder: folder.join(x_dir_name.as_str()),
+            });
         }
+        let extras = if cli::include_extras_value() {
+            season_extras(&season, vip, &x_dir_name, &folder.join(x_dir_name.as_str()))
+        } else {
+            vec![]
+        };
+        groups.push(ItemGroup {
+            title: x_dir_name,
+            items,
+            skipped,
+            resolved_by: Some(strategy),
+            extra: false,
+        });
+        groups.extend(extras);
     }
-    println!();
-    println!("{}全部完成", Emoji("✨", ""));
-    Ok(())
+    Ok(groups)
 }
 
-async fn download_collection_detail(mid: i64, sid: i64) -> crate::Result<()> {
-    let client = user::login_client().await?;
-    let mut current_page = 1;
-    let mut page_info = client
-        .collection_video_page(mid, sid, false, current_page, 20)
-        .await?;
+/// 解析番剧 : 先用id, 失败或者结果不对时改用网址, 返回结果和成功的方式;
+/// 加上 --parse-input-url 时只用网址
+async fn series_state(
+    client: &bilirust::Client,
+    id: &str,
+    url: &str,
+) -> crate::Result<(SsState, &'static str)> {
+    if cli::parse_input_url_value() {
+        let state = client.videos_info_by_url(url.to_owned()).await?;
+        check_series_state(id, &state).map_err(anyhow::Error::msg)?;
+        return Ok((state, "网址 (--parse-input-url)"));
+    }
+    let id_error = match client.videos_info(id.to_owned()).await {
+        Result::Ok(state) => match check_series_state(id, &state) {
+            Result::Ok(_) => return Ok((state, "id")),
+            Err(err) => err,
+        },
+        Err(err) => err.to_string(),
+    };
+    println!(
+        "{}",
+        style(format!("使用id解析失败 ({}), 改用网址解析", id_error)).yellow()
+    );
+    let url_error = match client.videos_info_by_url(url.to_owned()).await {
+        Result::Ok(state) => match check_series_state(id, &state) {
+            Result::Ok(_) => return Ok((state, "网址")),
+            Err(err) => err,
+        },
+        Err(err) => err.to_string(),
+    };
+    Err(anyhow::Error::msg(format!(
+        "无法解析剧集 {} : id ({}), 网址 ({})",
+        id, id_error, url_error
+    )))
+}
 
-    println!();
-    println!("{}获取到到合集：{}", Emoji("✨", ""), page_info.meta.name);
-    println!();
+/// 按ss解析一季, 网址使用播放页
+async fn season_state(
+    client: &bilirust::Client,
+    season_id: i64,
+) -> crate::Result<(SsState, &'static str)> {
+    let id = format!("ss{}", season_id);
+    let url = format!("https://www.bilibili.com/bangumi/play/{}", id);
+    series_state(client, &id, &url).await
+}
+
+/// 剧集为空, 或者解析的是ss/ep但结果中没有这一季/这一集时, 认为解析失败
+fn check_series_state(id: &str, state: &SsState) -> Result<(), String> {
+    if state.ep_list.is_empty() {
+        return Err("剧集为空".to_owned());
+    }
+    let ss_ids: Vec<i64> = state.ss_list.iter().map(|x| x.id).collect();
+    let ep_ids: Vec<i64> = state.ep_list.iter().map(|x| x.id).collect();
+    if !series_contains(id, &ss_ids, &ep_ids) {
+        return Err(format!("结果中没有 {}", id));
+    }
+    Result::Ok(())
+}
 
-    let folder = local::allowed_file_name(&page_info.meta.name);
-    tokio::fs::create_dir_all(&folder).await?;
+/// ss/ep 是否在解析结果中, 其他id (例如md) 不检查
+fn series_contains(id: &str, ss_ids: &[i64], ep_ids: &[i64]) -> bool {
+    let number = |prefix: &str| id.strip_prefix(prefix).and_then(|x| x.parse::<i64>().ok());
+    if let Some(season_id) = number("ss") {
+        return ss_ids.contains(&season_id);
+    }
+    if let Some(ep_id) = number("ep") {
+        return ep_ids.contains(&ep_id);
+    }
+    true
+}
 
-    let path = PathBuf::from(&folder);
-    loop {
-        //下载视频
-        for archive in page_info.archives {
-            println!();
-            println!("开始下载：{}", archive.title);
+/// 番剧中不能下载的集 : cid对应的原因, 因地区限制或付费无法获取番剧信息时整季都不能下载
+type Restrictions = Result<HashMap<i64, String>, String>;
+
+/// 番剧接口表示地区限制或付费的错误码, 其他错误不能说明这一季不能下载
+/// -10403 地区限制或大会员专享, 6002003 地区限制, 6002105 需要大会员或付费
+const SEASON_RESTRICTED_CODES: [i64; 3] = [-10403, 6002003, 6002105];
+
+/// 番剧信息, 因地区限制或付费失败时保留原因, 其他原因失败时提示并返回空 (限制未知)
+async fn fetch_season(season_id: i64) -> Result<Option<api::BangumiSeason>, String> {
+    match api::bangumi_season(&format!("season_id={}", season_id)).await {
+        Result::Ok(season) => Result::Ok(Some(season)),
+        Err(err) if is_season_restricted(&err) => Err(format!("无法获取番剧信息 : {}", err)),
+        Err(err) => {
+            println!(
+                "{}",
+                style(format!(
+                    "无法获取番剧信息 (ss{} : {}), 不检查地区和付费限制, 直接下载",
+                    season_id, err
+                ))
+                .yellow()
+            );
+            Result::Ok(None)
+        }
+    }
+}
 
-            let name = local::allowed_file_name(&archive.title);
-            let video_file = path.join(format!("{}.video", name));
-            let audio_file = path.join(format!("{}.audio", name));
-            let mix_file = path.join(format!("{}.mp4", name));
+fn is_season_restricted(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<api::ApiError>()
+        .map(|x| SEASON_RESTRICTED_CODES.contains(&x.code))
+        .unwrap_or(false)
+}
 
-            if mix_file.exists() {
-                println!("已存在：{}", archive.title);
-                continue;
+fn season_restrictions(
+    season: &Result<Option<api::BangumiSeason>, String>,
+    vip: bool,
+) -> Restrictions {
+    let season = match season.as_ref().map_err(|err| err.clone())? {
+        Some(season) => season,
+        None => return Result::Ok(HashMap::new()),
+    };
+    Result::Ok(
+        season
+            .episodes
+            .iter()
+            .filter_map(|ep| episode_restriction(ep, vip, season.paid).map(|x| (ep.cid, x)))
+            .collect(),
+    )
+}
+
+fn restriction_of(restrictions: &Restrictions, cid: i64) -> Option<String> {
+    match restrictions {
+        Result::Ok(map) => map.get(&cid).cloned(),
+        Err(err) => Some(err.clone()),
+    }
+}
+
+/// 季的花絮 (PV、OP/ED等), 每部分一组, 保存在季目录下的 Extras
+fn season_extras(
+    season: &Result<Option<api::BangumiSeason>, String>,
+    vip: bool,
+    season_title: &str,
+    season_folder: &Path,
+) -> Vec<ItemGroup> {
+    let season = match season {
+        Result::Ok(Some(season)) => season,
+        _ => return vec![],
+    };
+    let folder = season_folder.join(EXTRAS_FOLDER);
+    season
+        .sections
+        .iter()
+        .filter(|section| !section.episodes.is_empty())
+        .map(|section| {
+            let mut items = vec![];
+            let mut skipped = vec![];
+            for (i, ep) in section.episodes.iter().enumerate() {
+                let title = format!("{} {:02}. {}", section.title, i + 1, ep.title);
+                if let Some(reason) = episode_restriction(ep, vip, season.paid) {
+                    skipped.push((title, reason));
+                    continue;
+                }
+                items.push(DownloadItem {
+                    name: item_name(&title, &ep.bvid),
+                    bvid: ep.bvid.clone(),
+                    cid: Some(ep.cid),
+                    title,
+                    folder: folder.clone(),
+                });
+            }
+            ItemGroup {
+                title: format!("{} / {}", season_title, section.title),
+                items,
+                skipped,
+                resolved_by: None,
+                extra: true,
             }
+        })
+        .collect()
+}
+
+/// 预告、地区限制、付费、大会员的集不能下载
+fn episode_restriction(ep: &api::BangumiEpisode, vip: bool, paid: bool) -> Option<String> {
+    if ep.badge.contains("预告") {
+        return Some("预告".to_owned());
+    }
+    if ep.area_limit {
+        return Some("地区限制".to_owned());
+    }
+    if !paid && (ep.status == 12 || ep.badge.contains("付费") || ep.badge.contains("用券")) {
+        return Some("需要付费".to_owned());
+    }
+    if !vip && (ep.status == 13 || ep.badge.contains("会员")) {
+        return Some("需要大会员".to_owned());
+    }
+    None
+}
 
-            let bv_info = client.bv_info(archive.bvid).await?;
-            let media_url = client
-                .bv_download_url(bv_info.bvid, bv_info.cid, FNVAL_DASH, VIDEO_QUALITY_4K)
-                .await?;
-
-            let video_url = media_url.dash.video.first().unwrap().base_url.as_str();
-            let audio_url = media_url.dash.audio.first().unwrap().base_url.as_str();
-
-            //下载
-            down_file_to(video_url, &video_file, "下载视频").await;
-            println!("{}下载视频完成", Emoji("🚚 ", ""));
-
-            down_file_to(audio_url, &audio_file, "下载音频").await;
-            println!("{}下载音频完成", Emoji("🚚 ", ""));
-
-            println!("开始合并视频：{}", format!("{}.mp4", name));
-            ffmpeg::ffmpeg_merge_file(
-                vec![video_file.to_str().unwrap(), audio_file.to_str().unwrap()],
-                mix_file.to_str().unwrap(),
-            )
-            .unwrap();
-            println!("{}合并视频完成", Emoji("✨", ""));
-            let _ = std::fs::remove_file(&audio_file);
-            let _ = std::fs::remove_file(&video_file);
-            println!("{}完成数据清理", Emoji("🚚 ", ""));
+/// 分页获取用户合集 (collectiondetail) 中的全部视频
+pub(crate) async fn collection_items(
+    client: &bilirust::Client,
+    mid: i64,
+    sid: i64,
+) -> crate::Result<ItemGroup> {
+    let mut current_page = 1;
+    let mut page_info = client
+        .collection_video_page(mid, sid, false, current_page, 20)
+        .await?;
+    let title = page_info.meta.name.clone();
+    let folder = PathBuf::from(local::allowed_file_name(&title));
+    let mut items = vec![];
+    loop {
+        for archive in page_info.archives {
+            items.push(DownloadItem {
+                name: item_name(&archive.title, &archive.bvid),
+                bvid: archive.bvid,
+                cid: None,
+                title: archive.title,
+                folder: folder.clone(),
+            });
         }
-        // 获取下一页
         if page_info.page.page_size * page_info.page.page_num >= page_info.page.total {
             break;
         }
@@ -256,164 +428,349 @@ async fn download_collection_detail(mid: i64, sid: i64) -> crate::Result<()> {
             .collection_video_page(mid, sid, false, current_page, 20)
             .await?;
     }
-    println!();
-    println!("{}全部完成", Emoji("✨", ""));
-    Ok(())
+    Ok(ItemGroup {
+        title,
+        items,
+        skipped: vec![],
+        resolved_by: None,
+        extra: false,
+    })
 }
 
-async fn download_bv(bv: String) -> crate::Result<()> {
-    let client = user::login_client().await?;
-    println!();
-    println!("{}匹配到：{}", Emoji("✨", ""), bv.as_str());
-    println!();
-    let bv_info = client.bv_info(bv.clone()).await.unwrap();
-    println!(" {}", bv_info.title.as_str());
-    println!();
+/// 筛选UP主的投稿
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SpaceFilter {
+    /// 发布日期不早于
+    pub(crate) since: Option<NaiveDate>,
+    /// 发布日期不晚于
+    pub(crate) until: Option<NaiveDate>,
+    pub(crate) keyword: Option<String>,
+    /// 最短时长 (秒)
+    pub(crate) min_duration: Option<i64>,
+    /// 最长时长 (秒)
+    pub(crate) max_duration: Option<i64>,
+    /// 只取排序后的前N个
+    pub(crate) latest: Option<usize>,
+    /// pubdate / click / stow, 为空时按发布时间
+    pub(crate) order: Option<String>,
+}
 
-    let video_format = choose_video_format();
-    let format_value = video_format_parameters(video_format);
-    let media_url = client
-        .bv_download_url(bv.clone(), bv_info.cid, format_value, VIDEO_QUALITY_4K)
-        .await?;
-    match video_format {
-        "dash" => {
-            if media_url.support_formats.len() == 0 {
-                panic!("未找到！")
-            }
+impl SpaceFilter {
+    fn order(&self) -> &str {
+        self.order.as_deref().unwrap_or("pubdate")
+    }
 
-            //视频
-            let mut choose_string = vec![];
-            let mut choose_int = vec![];
-            for v in &media_url.dash.video {
-                if !choose_int.contains(&v.id) {
-                    choose_int.push(v.id);
-                    match v.id {
-                        120 => choose_string.push("4K".to_string()),
-                        116 => choose_string.push("1080P 60".to_string()),
-                        80 => choose_string.push("1080P".to_string()),
-                        64 => choose_string.push("720P".to_string()),
-                        32 => choose_string.push("480P".to_string()),
-                        16 => choose_string.push("360P".to_string()),
-                        _ => choose_string.push(format!("VEDIO-{}", v.id)),
-                    }
-                }
+    fn matches(&self, video: &api::SpaceVideo) -> bool {
+        let date = match Local.timestamp_opt(video.created, 0).single() {
+            Some(time) => time.date_naive(),
+            None => return false,
+        };
+        !(self.since.is_some_and(|x| date < x)
+            || self.until.is_some_and(|x| date > x)
+            || self.min_duration.is_some_and(|x| video.length < x)
+            || self.max_duration.is_some_and(|x| video.length > x))
+    }
+
+    /// 按发布时间排序时, 早于since的视频之后都不需要再看
+    fn past_since(&self, video: &api::SpaceVideo) -> bool {
+        if self.order() != "pubdate" {
+            return false;
+        }
+        match (self.since, Local.timestamp_opt(video.created, 0).single()) {
+            (Some(since), Some(time)) => time.date_naive() < since,
+            _ => false,
+        }
+    }
+}
+
+/// 分页获取UP主的投稿, 数量够了或者超出日期范围时提前结束
+pub(crate) async fn space_items(mid: i64, filter: &SpaceFilter) -> crate::Result<ItemGroup> {
+    let keyword = filter.keyword.clone().unwrap_or_default();
+    let mut author = String::new();
+    let mut items = vec![];
+    let mut current_page = 1;
+    'pages: loop {
+        let page = api::space_videos(
+            mid,
+            current_page,
+            SPACE_PAGE_SIZE,
+            filter.order(),
+            keyword.as_str(),
+        )
+        .await?;
+        if page.videos.is_empty() {
+            break;
+        }
+        for video in page.videos {
+            if author.is_empty() {
+                author = video.author.clone();
             }
-            let quality_video = choose_int[Select::new()
-                .with_prompt("选择视频质量")
-                .default(0)
-                .items(&choose_string)
-                .interact()
-                .unwrap()];
-
-            // 音频
-            let mut choose_string = vec![];
-            let mut choose_int = vec![];
-            for a in &media_url.dash.audio {
-                if !choose_int.contains(&a.id) {
-                    choose_int.push(a.id);
-                    match a.id {
-                        30216 => choose_string.push("64K".to_string()),
-                        30232 => choose_string.push("132K".to_string()),
-                        30280 => choose_string.push("192K".to_string()),
-                        _ => choose_string.push(format!("AUDIO-{}", a.id)),
-                    }
-                }
+            if filter.past_since(&video) {
+                break 'pages;
             }
-            let quality_audio = choose_int[Select::new()
-                .with_prompt("选择音频质量")
-                .default(0)
-                .items(&choose_string)
-                .interact()
-                .unwrap()];
-
-            // 下载
-            let mut video: Option<&Video> = None;
-            for x in &media_url.dash.video {
-                if x.id == quality_video {
-                    video = Some(x);
-                    break;
-                }
+            if !filter.matches(&video) {
+                continue;
             }
-            let mut audio: Option<&Audio> = None;
-            for x in &media_url.dash.audio {
-                if x.id == quality_audio {
-                    audio = Some(x);
-                    break;
-                }
+            items.push(video);
+            if filter.latest.is_some_and(|x| items.len() >= x) {
+                break 'pages;
             }
+        }
+        if current_page * SPACE_PAGE_SIZE >= page.count {
+            break;
+        }
+        current_page += 1;
+    }
+    let title = if author.is_empty() {
+        mid.to_string()
+    } else {
+        format!("{} ({})", author, mid)
+    };
+    let folder = PathBuf::from(local::allowed_file_name(&title));
+    Ok(ItemGroup {
+        items: items
+            .into_iter()
+            .map(|video| DownloadItem {
+                name: item_name(&video.title, &video.bvid),
+                bvid: video.bvid,
+                cid: None,
+                title: video.title,
+                folder: folder.clone(),
+            })
+            .collect(),
+        title,
+        skipped: vec![],
+        resolved_by: None,
+        extra: false,
+    })
+}
 
-            //没找到应该重新来过 递归
-            let video = video.unwrap();
-            let audio = audio.unwrap();
-
-            //构建路径
-            let name = local::allowed_file_name(&bv_info.title);
-
-            let video_file = PathBuf::from(format!("{}.video", name));
-            let audio_file = PathBuf::from(format!("{}.audio", name));
-            let mix_file = PathBuf::from(format!("{}.mp4", name));
+/// 分页获取视频列表 (seriesdetail) 中的全部视频
+pub(crate) async fn series_detail_items(mid: i64, sid: i64) -> crate::Result<ItemGroup> {
+    let title = api::series_name(sid).await?;
+    let folder = PathBuf::from(local::allowed_file_name(&title));
+    let mut items = vec![];
+    let mut current_page = 1;
+    loop {
+        let (archives, total) = api::series_archives(mid, sid, current_page, 20).await?;
+        if archives.is_empty() {
+            break;
+        }
+        for archive in archives {
+            items.push(DownloadItem {
+                name: item_name(&archive.title, &archive.bvid),
+                bvid: archive.bvid,
+                cid: None,
+                title: archive.title,
+                folder: folder.clone(),
+            });
+        }
+        if current_page * 20 >= total {
+            break;
+        }
+        current_page += 1;
+    }
+    Ok(ItemGroup {
+        title,
+        items,
+        skipped: vec![],
+        resolved_by: None,
+        extra: false,
+    })
+}
 
-            println!("{}下载到文件 : {}", Emoji("✨", ""), mix_file.display());
-            if mix_file.exists() {
-                panic!("文件已存在");
+/// 分页获取收藏夹中的视频, 已失效和不是视频的跳过
+pub(crate) async fn fav_items(fid: i64) -> crate::Result<ItemGroup> {
+    let mut title = String::new();
+    let mut items = vec![];
+    let mut skipped = vec![];
+    let mut current_page = 1;
+    loop {
+        let page = api::fav_medias(fid, current_page, 20).await?;
+        if title.is_empty() {
+            title = page.folder.title.clone();
+        }
+        for media in page.medias {
+            if media.media_type != 2 {
+                skipped.push((media.title, "不是视频".to_owned()));
+                continue;
             }
-
-            //下载
-            down_file_to(&video.base_url, &video_file, "下载视频").await;
-            println!("{}下载视频完成", Emoji("🚚 ", ""));
-
-            down_file_to(&audio.base_url, &audio_file, "下载音频").await;
-            println!("{}下载音频完成", Emoji("🚚 ", ""));
-
-            println!("开始合并视频：{}", format!("{}.mp4", name));
-            ffmpeg::ffmpeg_merge_file(
-                vec![video_file.to_str().unwrap(), audio_file.to_str().unwrap()],
-                mix_file.to_str().unwrap(),
-            )
-            .unwrap();
-            println!("{}合并视频完成", Emoji("✨", ""));
-            let _ = std::fs::remove_file(&audio_file);
-            let _ = std::fs::remove_file(&video_file);
-            println!("{}完成数据清理", Emoji("🚚 ", ""));
+            if media.attr != 0 || media.bvid.is_empty() {
+                skipped.push((
+                    format!("{} ({})", media.title, media.bvid),
+                    "已失效".to_owned(),
+                ));
+                continue;
+            }
+            items.push(media);
         }
-        "mp4" => {
-            let name = local::allowed_file_name(&bv_info.title);
-                let mp4_file = PathBuf::from(format!("{}.mp4", name));
-                println!("下载到文件 : {}", mp4_file.display());
-                if mp4_file.exists() {
-                    panic!("文件已存在");
-                }
-                down_file_to(&media_url.durl.first().unwrap().url, &mp4_file, "下载中").await;
-                println!("下载完成");
+        if !page.has_more {
+            break;
         }
-        _ => panic!("e2"),
+        current_page += 1;
     }
-    Ok(())
+    if title.is_empty() {
+        title = fid.to_string();
+    }
+    let folder = PathBuf::from(local::allowed_file_name(&title));
+    Ok(ItemGroup {
+        items: items
+            .into_iter()
+            .map(|media| DownloadItem {
+                name: item_name(&media.title, &media.bvid),
+                bvid: media.bvid,
+                cid: None,
+                title: media.title,
+                folder: folder.clone(),
+            })
+            .collect(),
+        title,
+        skipped,
+        resolved_by: None,
+        extra: false,
+    })
 }
 
-async fn down_file_to(url: &str, file: &Path, title: &str) {
-    let checkpoint = if cli::resume_download_value() && file.exists() {
-        file.metadata().unwrap().len()
+/// 交互选择要下载的组 : 指定 --choose-seasons 时选择季, 有花絮时选择花絮
+pub(crate) fn choose_groups(groups: Vec<ItemGroup>) -> crate::Result<Vec<ItemGroup>> {
+    let (extras, groups): (Vec<ItemGroup>, Vec<ItemGroup>) =
+        groups.into_iter().partition(|x| x.extra);
+    let mut groups = if cli::choose_seasons_value() {
+        choose_from("请选择要下载的合集", groups)?
+    } else {
+        groups
+    };
+    groups.extend(choose_from("请选择要下载的花絮", extras)?);
+    Ok(groups)
+}
+
+/// 多选, 默认全部选中, 没有可选的组时不提示
+fn choose_from(prompt: &str, groups: Vec<ItemGroup>) -> crate::Result<Vec<ItemGroup>> {
+    if groups.is_empty() {
+        return Ok(groups);
+    }
+    let titles: Vec<String> = groups
+        .iter()
+        .map(|x| format!("{} ({} 个视频)", x.title, x.items.len()))
+        .collect();
+    let selects = dialoguer::MultiSelect::new()
+        .with_prompt(prompt)
+        .items(&titles)
+        .defaults(&vec![true; titles.len()])
+        .interact()?;
+    Ok(groups
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| selects.contains(i))
+        .map(|(_, x)| x)
+        .collect())
+}
+
+/// 交互选择单个视频的格式、清晰度和音质, 返回 (格式, 清晰度, 音质)
+pub(crate) async fn choose_options(
+    client: &bilirust::Client,
+    item: &DownloadItem,
+) -> crate::Result<(VideoFormat, Option<i64>, Option<i64>)> {
+    let format = [VideoFormat::Dash, VideoFormat::Mp4][Select::new()
+        .with_prompt("选择视频格式")
+        .default(0)
+        .items(&["dash (高清)", "mp4"])
+        .interact()?];
+    if format == VideoFormat::Mp4 {
+        return Ok((format, None, None));
+    }
+    let cid = match item.cid {
+        Some(cid) => cid,
+        None => client.bv_info(item.bvid.clone()).await?.cid,
+    };
+    let media_url = client
+        .bv_download_url(item.bvid.clone(), cid, FNVAL_DASH, VIDEO_QUALITY_4K)
+        .await?;
+
+    //视频
+    let video_ids: Vec<i64> = media_url.dash.video.iter().map(|x| x.id).unique().collect();
+    if video_ids.is_empty() {
+        return Err(anyhow::Error::msg(format!("未找到视频 : {}", item.title)));
+    }
+    if let Some(note) = user::unavailable_qualities(&video_ids).await {
+        println!("{}", style(note).yellow());
+    }
+    // 默认选中配置的清晰度
+    let videos: Vec<api::DashVideo> = media_url
+        .dash
+        .video
+        .iter()
+        .map(api::DashVideo::from)
+        .collect();
+    let default = queue::pick_video(&videos, config::quality(), None)
+        .and_then(|v| video_ids.iter().position(|x| *x == v.id))
+        .unwrap_or(0);
+    let names: Vec<String> = video_ids.iter().map(|x| video_quality_name(*x)).collect();
+    let quality = video_ids[Select::new()
+        .with_prompt("选择视频质量")
+        .default(default)
+        .items(&names)
+        .interact()?];
+
+    // 音频
+    let audio_ids: Vec<i64> = media_url.dash.audio.iter().map(|x| x.id).unique().collect();
+    if audio_ids.is_empty() {
+        return Err(anyhow::Error::msg(format!("未找到音频 : {}", item.title)));
+    }
+    let names: Vec<String> = audio_ids.iter().map(|x| audio_quality_name(*x)).collect();
+    let audio_quality = audio_ids[Select::new()
+        .with_prompt("选择音频质量")
+        .default(0)
+        .items(&names)
+        .interact()?];
+    Ok((format, Some(quality), Some(audio_quality)))
+}
+
+/// 下载视频封面到保存目录, 没有封面或下载失败时返回空, 不影响视频下载
+pub(crate) async fn down_cover(item: &DownloadItem) -> Option<PathBuf> {
+    let pic = api::video_view(&item.bvid).await.ok()?.pic;
+    let extension = match pic.rsplit('.').next()?.to_lowercase().as_str() {
+        "png" => "png",
+        _ => "jpg",
+    };
+    let bytes = request_resource(&pic).await.ok()?.bytes().await.ok()?;
+    let file = item
+        .folder
+        .join(format!("{}.cover.{}", item.name, extension));
+    tokio::fs::write(&file, bytes).await.ok()?;
+    Some(file)
+}
+
+/// 下载文件并在pb上显示进度, resume 为true时从已有文件末尾继续下载
+pub(crate) async fn down_file_with(
+    url: &str,
+    file: &Path,
+    pb: &ProgressBar,
+    resume: bool,
+) -> crate::Result<()> {
+    let checkpoint = if resume && file.exists() {
+        file.metadata()?.len()
     } else {
         0
     };
-    let rsp = request_resource(url).await;
-    let size = content_length(&rsp).unwrap();
+    let rsp = request_resource(url).await?;
+    let size = content_length(&rsp)?;
+    pb.set_length(size);
+    pb.set_position(checkpoint);
 
     let (rsp, file) = if checkpoint == 0 {
-        (rsp, tokio::fs::File::create(&file).await.unwrap())
+        (rsp, tokio::fs::File::create(&file).await?)
     } else {
         if size == checkpoint {
-            return;
+            return Ok(());
         }
         drop(rsp);
         (
-            request_resource_rang(url, checkpoint).await,
+            request_resource_rang(url, checkpoint).await?,
             tokio::fs::OpenOptions::new()
                 .append(true)
                 .open(file)
-                .await
-                .unwrap(),
+                .await?,
         )
     };
     let mut writer = BufWriter::with_capacity(1 << 18, file);
@@ -428,76 +785,100 @@ async fn down_file_to(url: &str, file: &Path, title: &str) {
 
     let (sender, mut receiver) = tokio::sync::mpsc::channel(1 << 10);
 
+    let rate_limit = config::rate_limit();
     let sjb = tokio::spawn(async move {
+        let started = std::time::Instant::now();
+        let mut received = 0u64;
         loop {
-            let read = reader.read(buffer.as_mut()).await.unwrap();
+            let read = reader.read(buffer.as_mut()).await?;
             if read == 0 {
                 break;
             }
-            sender.send(buffer[0..read].to_vec()).await.unwrap();
+            // 限速 : 读取得比限速快时等待, 读取端等待时服务器的发送也会变慢
+            if let Some(rate_limit) = rate_limit {
+                received += read as u64;
+                let expected =
+                    std::time::Duration::from_secs_f64(received as f64 / rate_limit as f64);
+                let elapsed = started.elapsed();
+                if expected > elapsed {
+                    tokio::time::sleep(expected - elapsed).await;
+                }
+            }
+            // 写入端出错时接收端已关闭, 错误由写入端返回
+            if sender.send(buffer[0..read].to_vec()).await.is_err() {
+                break;
+            }
         }
+        Ok(())
     });
 
-    let title = title.to_string();
+    let pb = pb.clone();
     let rjb = tokio::spawn(async move {
-        let pb = ProgressBar::new(size);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    &*("".to_owned()
-                        + "{spinner:.green}  "
-                        + title.as_str()
-                        + " [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}"),
-                )
-                .unwrap()
-                .progress_chars("#>-"),
-        );
         let mut download_count = checkpoint;
-        pb.set_position(download_count);
         while let Some(msg) = receiver.recv().await {
-            writer.write_all(&msg).await.unwrap();
+            writer.write_all(&msg).await?;
             download_count += msg.len() as u64;
             pb.set_position(download_count);
         }
-        pb.finish_and_clear();
-        writer.flush().await.unwrap();
+        writer.flush().await?;
+        Ok(())
     });
-    //     let (s,r) = tokio::join!(rjb,sjb);
-    //    s.unwrap();
-    //    r.unwrap();
-    sjb.await.unwrap();
-    rjb.await.unwrap();
+    sjb.await??;
+    rjb.await??;
+    Ok(())
 }
 
-fn choose_video_format() -> &'static str {
-    ["dash", "mp4"][Select::new()
-        .with_prompt("选择视频格式")
-        .default(0)
-        .items(&["dash (高清)", "mp4"])
-        .interact()
-        .unwrap()]
+/// 删除临时文件, 指定 --keep-temp 时保留并返回提示
+pub(crate) fn remove_temp_files(files: &[&Path]) -> Option<String> {
+    if cli::keep_temp_value() {
+        return Some(format!(
+            "{}保留临时文件 : {}",
+            Emoji("🚚 ", ""),
+            files.iter().map(|x| x.display()).join(" , ")
+        ));
+    }
+    for file in files {
+        let _ = std::fs::remove_file(file);
+    }
+    None
 }
 
-fn video_format_parameters(format_str: &str) -> i64 {
-    match format_str {
-        "mp4" => FNVAL_MP4,
-        "dash" => FNVAL_DASH,
-        _ => panic!("格式不正确"),
+/// 视频清晰度的名称
+pub(crate) fn video_quality_name(id: i64) -> String {
+    match id {
+        120 => "4K".to_string(),
+        116 => "1080P 60".to_string(),
+        112 => "1080P+".to_string(),
+        80 => "1080P".to_string(),
+        64 => "720P".to_string(),
+        32 => "480P".to_string(),
+        16 => "360P".to_string(),
+        _ => format!("VEDIO-{}", id),
     }
 }
 
-async fn request_resource(url: &str) -> reqwest::Response {
-    reqwest::Client::new().get(url).header(
+/// 音频质量的名称
+fn audio_quality_name(id: i64) -> String {
+    match id {
+        30216 => "64K".to_string(),
+        30232 => "132K".to_string(),
+        30280 => "192K".to_string(),
+        _ => format!("AUDIO-{}", id),
+    }
+}
+
+async fn request_resource(url: &str) -> crate::Result<reqwest::Response> {
+    Ok(reqwest::Client::new().get(url).header(
         "user-agent",
         "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/98.0.4758.80 Safari/537.36",
-    ).header("referer", "https://www.bilibili.com").send().await.unwrap().error_for_status().unwrap()
+    ).header("referer", "https://www.bilibili.com").send().await?.error_for_status()?)
 }
 
-async fn request_resource_rang(url: &str, begin: u64) -> reqwest::Response {
-    reqwest::Client::new().get(url).header(
+async fn request_resource_rang(url: &str, begin: u64) -> crate::Result<reqwest::Response> {
+    Ok(reqwest::Client::new().get(url).header(
         "user-agent",
         "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/98.0.4758.80 Safari/537.36",
-    ).header("referer", "https://www.bilibili.com").header("Range",format!("bytes={}-",begin)).send().await.unwrap().error_for_status().unwrap()
+    ).header("referer", "https://www.bilibili.com").header("Range",format!("bytes={}-",begin)).send().await?.error_for_status()?)
 }
 
 fn content_length(rsp: &reqwest::Response) -> crate::Result<u64> {
@@ -510,3 +891,40 @@ fn content_length(rsp: &reqwest::Response) -> crate::Result<u64> {
         .parse()
         .with_context(|| "未能取得文件长度, HEADER不能识别未数字")?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(code: i64) -> anyhow::Error {
+        anyhow::Error::new(api::ApiError {
+            code,
+            message: String::new(),
+        })
+    }
+
+    #[test]
+    fn series_must_contain_id() {
+        assert!(series_contains("ss10", &[10], &[100, 101]));
+        assert!(!series_contains("ss11", &[10], &[100, 101]));
+        assert!(series_contains("ep101", &[10], &[100, 101]));
+        assert!(!series_contains("ep102", &[10], &[100, 101]));
+        assert!(series_contains("md1", &[10], &[]));
+    }
+
+    #[test]
+    fn only_known_codes_restrict_season() {
+        assert!(is_season_restricted(&api_error(-10403)));
+        assert!(is_season_restricted(&api_error(6002003)));
+        assert!(!is_season_restricted(&api_error(-404)));
+        assert!(!is_season_restricted(&anyhow::Error::msg("timeout")));
+    }
+
+    #[test]
+    fn unknown_season_downloads_everything() {
+        let unknown = season_restrictions(&Result::Ok(None), false);
+        assert_eq!(restriction_of(&unknown, 1), None);
+        let restricted = season_restrictions(&Err("地区限制".to_owned()), false);
+        assert_eq!(restriction_of(&restricted, 1), Some("地区限制".to_owned()));
+    }
+}
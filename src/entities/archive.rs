@@ -0,0 +1,18 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::{ActiveModelBehavior, EntityTrait};
+
+/// 已经下载过的视频
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "archive")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub bvid: String,
+    pub title: String,
+    pub file: String,
+    pub created_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1 +1,4 @@
-pub(crate) mod property;
\ No newline at end of file
+pub(crate) mod archive;
+pub(crate) mod property;
+pub(crate) mod serve_job;
+pub(crate) mod subscription;
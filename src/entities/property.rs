@@ -1,6 +1,6 @@
+use crate::local::{create_index, index_exists};
 use sea_orm::entity::prelude::*;
 use sea_orm::{ActiveModelBehavior, EntityTrait};
-use crate::local::{create_index, index_exists};
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "property")]
@@ -15,8 +15,9 @@ pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
 
-pub(crate) async fn init_indexes(db: &DatabaseConnection) {
-    if !index_exists(db, "property", "idx_k").await {
-        create_index(db, "property", vec!["k"], "idx_k").await;
+pub(crate) async fn init_indexes<C: ConnectionTrait>(db: &C) -> crate::Result<()> {
+    if !index_exists(db, "property", "idx_k").await? {
+        create_index(db, "property", vec!["k"], "idx_k").await?;
     }
+    Ok(())
 }
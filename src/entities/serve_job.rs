@@ -0,0 +1,29 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::{ActiveModelBehavior, EntityTrait};
+
+/// 通过接口提交的下载任务, 重启后恢复
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "serve_job")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    pub url: String,
+    pub bvid: String,
+    pub cid: Option<i64>,
+    pub title: String,
+    pub folder: String,
+    pub name: String,
+    pub quality: Option<i64>,
+    /// dash 或 mp4
+    pub format: String,
+    pub audio_quality: Option<i64>,
+    pub state: String,
+    pub message: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
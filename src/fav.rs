@@ -0,0 +1,28 @@
+use crate::{api, user};
+use console::{style, Emoji};
+
+/// 列出当前账号创建的收藏夹
+pub(crate) async fn list() -> crate::Result<()> {
+    // 确认已经登录
+    user::login_client().await?;
+    let mid = user::my_mid().await?;
+    let folders = api::fav_folders(mid).await?;
+    if folders.is_empty() {
+        println!("没有收藏夹");
+        return Ok(());
+    }
+    println!("{}收藏夹 :", Emoji("✨", ""));
+    for folder in folders {
+        println!(
+            "[{}] {} ({} 个视频)",
+            folder.id,
+            style(folder.title).bold(),
+            folder.media_count
+        );
+        println!(
+            "    https://space.bilibili.com/{}/favlist?fid={}",
+            mid, folder.id
+        );
+    }
+    Ok(())
+}
@@ -1,112 +1,927 @@
+use crate::{config, local};
+#[cfg(not(feature = "ffmpeg_api"))]
+use anyhow::Context;
+use indicatif::ProgressBar;
+#[cfg(not(feature = "ffmpeg_api"))]
+use indicatif::ProgressStyle;
+#[cfg(not(feature = "ffmpeg_api"))]
+use itertools::Itertools;
+use once_cell::sync::OnceCell;
+#[cfg(not(feature = "ffmpeg_api"))]
+use std::collections::VecDeque;
+#[cfg(not(feature = "ffmpeg_api"))]
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 #[cfg(not(feature = "ffmpeg_api"))]
 use std::process::{Command, Stdio};
+use std::sync::atomic::AtomicBool;
+#[cfg(not(feature = "ffmpeg_api"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(not(feature = "ffmpeg_api"))]
+use std::sync::Mutex;
+#[cfg(not(feature = "ffmpeg_api"))]
+use std::time::Duration;
 
-#[cfg(feature = "ffmpeg_api")]
-pub(crate) fn ffmpeg_run_version() -> crate::Result<()> {
+/// 合并失败时保留的ffmpeg错误输出行数
+#[cfg(not(feature = "ffmpeg_api"))]
+const STDERR_TAIL_LINES: usize = 20;
+
+/// 旧版本保存ffmpeg路径的配置项, 现在保存在配置文件中
+const FFMPEG_PATH_PROPERTY: &str = "ffmpeg_path";
+
+static FFMPEG_INFO: OnceCell<FfmpegInfo> = OnceCell::new();
+
+/// 检测到的ffmpeg版本及支持的格式/编码器, 格式和编码器在首次使用时检测并缓存
+pub(crate) struct FfmpegInfo {
+    pub(crate) path: String,
+    pub(crate) version: String,
+    muxers: OnceCell<Vec<String>>,
+    demuxers: OnceCell<Vec<String>>,
+    encoders: OnceCell<Vec<String>>,
+}
+
+impl FfmpegInfo {
+    pub(crate) fn muxers(&self) -> &[String] {
+        self.muxers
+            .get_or_init(|| ffmpeg_list(&self.path, "-muxers"))
+    }
+
+    pub(crate) fn demuxers(&self) -> &[String] {
+        self.demuxers
+            .get_or_init(|| ffmpeg_list(&self.path, "-demuxers"))
+    }
+
+    pub(crate) fn encoders(&self) -> &[String] {
+        self.encoders
+            .get_or_init(|| ffmpeg_list(&self.path, "-encoders"))
+    }
+
+    /// 需要某个封装格式才能使用的功能, 不支持时返回说明原因的错误
+    pub(crate) fn require_muxer(&self, name: &str, feature: &str) -> crate::Result<()> {
+        self.require(self.muxers(), "封装格式", name, feature)
+    }
+
+    /// 需要某个解封装格式才能使用的功能
+    pub(crate) fn require_demuxer(&self, name: &str, feature: &str) -> crate::Result<()> {
+        self.require(self.demuxers(), "解封装格式", name, feature)
+    }
+
+    /// 需要某个编码器才能使用的功能, 例如转码和字幕压制
+    pub(crate) fn require_encoder(&self, name: &str, feature: &str) -> crate::Result<()> {
+        self.require(self.encoders(), "编码器", name, feature)
+    }
+
+    fn require(&self, list: &[String], kind: &str, name: &str, feature: &str) -> crate::Result<()> {
+        if list.iter().any(|x| x == name) {
+            return Ok(());
+        }
+        Err(anyhow::Error::msg(format!(
+            "当前ffmpeg ({}) 不支持{} {}, 无法{}",
+            self.version, kind, name, feature
+        )))
+    }
+}
+
+/// ffmpeg路径 : 配置 ffmpeg_path (包括环境变量) > 旧版本保存在数据库中的路径 > PATH中的ffmpeg
+pub(crate) async fn ffmpeg_path() -> crate::Result<String> {
+    if let Some(path) = config::ffmpeg_path() {
+        return Ok(path);
+    }
+    let path = local::load_property(FFMPEG_PATH_PROPERTY.to_owned()).await?;
+    if !path.is_empty() {
+        return Ok(path);
+    }
+    Ok("ffmpeg".to_owned())
+}
+
+/// 检查ffmpeg是否可用, 只在需要合并视频的命令中调用
+pub(crate) async fn ffmpeg_check() -> crate::Result<&'static FfmpegInfo> {
+    if let Some(info) = FFMPEG_INFO.get() {
+        return Ok(info);
+    }
+    let path = ffmpeg_path().await?;
+    let version = ffmpeg_version(&path)?;
+    Ok(FFMPEG_INFO.get_or_init(|| FfmpegInfo {
+        path,
+        version,
+        muxers: OnceCell::new(),
+        demuxers: OnceCell::new(),
+        encoders: OnceCell::new(),
+    }))
+}
+
+/// 已检测的ffmpeg, 必须先调用 ffmpeg_check
+pub(crate) fn ffmpeg_info() -> &'static FfmpegInfo {
+    FFMPEG_INFO.get().expect("ffmpeg未检测")
+}
+
+/// 打印ffmpeg信息及可用功能, 可同时保存ffmpeg路径到配置文件
+pub(crate) async fn ffmpeg_report(path: &Option<String>) -> crate::Result<()> {
+    if let Some(path) = path {
+        config::set_value("ffmpeg_path", path)?;
+    }
+    let info = ffmpeg_check().await?;
+    println!("路径 : {}", info.path);
+    println!("版本 : {}", info.version);
+    println!("封装格式 : {} 个", info.muxers().len());
+    println!("解封装格式 : {} 个", info.demuxers().len());
+    println!("编码器 : {} 个", info.encoders().len());
+    println!();
+    for check in [
+        info.require_muxer("mp4", "合并视频"),
+        info.require_demuxer("concat", "拼接分段视频"),
+        info.require_encoder("libx264", "转码为H.264"),
+        info.require_encoder("libx265", "转码为H.265"),
+    ] {
+        if let Err(err) = check {
+            println!("{}", err);
+        }
+    }
     Ok(())
 }
 
+#[cfg(feature = "ffmpeg_api")]
+fn ffmpeg_version(_path: &str) -> crate::Result<String> {
+    let version = unsafe { std::ffi::CStr::from_ptr(rsmpeg::ffi::av_version_info()) };
+    Ok(format!("libav {}", version.to_string_lossy()))
+}
+
 #[cfg(not(feature = "ffmpeg_api"))]
-pub(crate) fn ffmpeg_run_version() -> crate::Result<()> {
-    let mut cmd = Command::new("ffmpeg");
-    cmd.stderr(Stdio::null());
-    cmd.stdout(Stdio::null());
-    cmd.arg("-version");
-    match cmd.status() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(anyhow::Error::msg("未找到ffmpeg, 请先安装ffmpeg.")),
+fn ffmpeg_version(path: &str) -> crate::Result<String> {
+    let output = Command::new(path)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|_| {
+            anyhow::Error::msg(format!(
+                "未找到ffmpeg ({}), 请先安装ffmpeg, 或通过环境变量 BILI_FFMPEG_PATH / bili ffmpeg --path 指定路径.",
+                path
+            ))
+        })?;
+    if !output.status.success() {
+        return Err(anyhow::Error::msg(format!("ffmpeg ({}) 无法运行", path)));
     }
+    // ffmpeg version 5.1.2 Copyright (c) 2000-2022 the FFmpeg developers
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("ffmpeg version "))
+        .and_then(|line| line.split_whitespace().next())
+        .unwrap_or("unknown");
+    Ok(version.to_owned())
 }
 
+/// 列出libav编译进来的格式或编码器
 #[cfg(feature = "ffmpeg_api")]
-pub(crate) fn ffmpeg_merge_file(list: Vec<&str>, output: &str) -> bilirust::Result<()> {
-    ffmpeg_api::ffmpeg_merge_files(list, output)
-}
-
-/// 合并音频视频
-#[cfg(not(feature = "ffmpeg_api"))]
-pub(crate) fn ffmpeg_merge_file(list: Vec<&str>, output: &str) -> bilirust::Result<()> {
-    let mut cmd = Command::new("ffmpeg");
-    cmd.stderr(Stdio::null());
-    cmd.stdout(Stdio::null());
-    for x in list {
-        cmd.arg("-i");
-        cmd.arg(x);
-    }
-    cmd.arg("-vcodec");
-    cmd.arg("copy");
-    cmd.arg("-acodec");
-    cmd.arg("copy");
-    cmd.arg(output);
-    let status = cmd.status().unwrap();
-    if status.code().unwrap() == 0 {
+fn ffmpeg_list(_path: &str, kind: &str) -> Vec<String> {
+    use rsmpeg::ffi;
+    use std::ffi::CStr;
+    let mut names = vec![];
+    let mut opaque = std::ptr::null_mut();
+    unsafe {
+        match kind {
+            "-muxers" => loop {
+                let format = ffi::av_muxer_iterate(&mut opaque);
+                if format.is_null() {
+                    break;
+                }
+                names.push(
+                    CStr::from_ptr((*format).name)
+                        .to_string_lossy()
+                        .into_owned(),
+                );
+            },
+            "-demuxers" => loop {
+                let format = ffi::av_demuxer_iterate(&mut opaque);
+                if format.is_null() {
+                    break;
+                }
+                names.push(
+                    CStr::from_ptr((*format).name)
+                        .to_string_lossy()
+                        .into_owned(),
+                );
+            },
+            _ => loop {
+                let codec = ffi::av_codec_iterate(&mut opaque);
+                if codec.is_null() {
+                    break;
+                }
+                if ffi::av_codec_is_encoder(codec) != 0 {
+                    names.push(CStr::from_ptr((*codec).name).to_string_lossy().into_owned());
+                }
+            },
+        }
+    }
+    names
+        .iter()
+        .flat_map(|x| x.split(','))
+        .map(|x| x.to_owned())
+        .collect()
+}
+
+/// 解析 `ffmpeg -muxers` / `-demuxers` / `-encoders` 的输出, 表头以 `--` 开始的行结束
+#[cfg(not(feature = "ffmpeg_api"))]
+fn ffmpeg_list(path: &str, kind: &str) -> Vec<String> {
+    let output = match Command::new(path)
+        .arg("-hide_banner")
+        .arg(kind)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(_) => return vec![],
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip_while(|line| !line.trim().starts_with("--"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .flat_map(|names| names.split(','))
+        .map(|name| name.to_owned())
+        .collect()
+}
+
+/// 合并/拼接时写入输出文件的附加信息
+#[derive(Default)]
+pub(crate) struct MuxOptions {
+    /// 全局元数据, 例如 title / artist / comment
+    pub(crate) metadata: Vec<(String, String)>,
+    /// 封面图片 (jpg/png), 作为 attached_pic 写入, 输出是mp4所以不支持其他附件
+    pub(crate) cover: Option<PathBuf>,
+    /// 输出流的语言 (输出流序号, ISO 639-2 代码), 输出流按输入文件顺序排列, 封面在最后
+    pub(crate) languages: Vec<(usize, String)>,
+    /// 在已有的进度条上显示进度(单位为微秒), 为空时单独显示一个进度条
+    pub(crate) progress: Option<ProgressBar>,
+    /// 设置后停止封装, 命令行实现会结束ffmpeg进程
+    pub(crate) cancelled: Arc<AtomicBool>,
+}
+
+fn cancelled_error() -> anyhow::Error {
+    anyhow::Error::msg("已取消")
+}
+
+/// 音视频封装, CLI和rsmpeg两种实现按 ffmpeg_api 特性二选一
+pub(crate) trait Muxer: Sync {
+    /// 把多个输入(例如视频和音频)的全部流合并到一个文件, 只复制不转码
+    fn merge(&self, inputs: &[&Path], output: &Path, options: &MuxOptions) -> crate::Result<()>;
+
+    /// 把多个分段首尾相接拼成一个文件, 只复制不转码
+    fn concat(&self, segments: &[&Path], output: &Path, options: &MuxOptions) -> crate::Result<()>;
+}
+
+/// 当前编译进来的封装实现
+#[cfg(feature = "ffmpeg_api")]
+pub(crate) fn muxer() -> &'static dyn Muxer {
+    &ffmpeg_api::ApiMuxer
+}
+
+/// 当前编译进来的封装实现
+#[cfg(not(feature = "ffmpeg_api"))]
+pub(crate) fn muxer() -> &'static dyn Muxer {
+    &CliMuxer
+}
+
+/// 调用ffmpeg命令行
+#[cfg(not(feature = "ffmpeg_api"))]
+struct CliMuxer;
+
+#[cfg(not(feature = "ffmpeg_api"))]
+impl Muxer for CliMuxer {
+    fn merge(&self, inputs: &[&Path], output: &Path, options: &MuxOptions) -> crate::Result<()> {
+        let mut cmd = ffmpeg_command();
+        for (i, input) in inputs.iter().enumerate() {
+            cmd.arg("-i").arg(input);
+            cmd.arg("-map").arg(i.to_string());
+        }
+        let stream_count = match options.cover {
+            Some(_) => inputs.iter().map(|x| ffmpeg_probe(x).0).sum(),
+            None => 0,
+        };
+        ffmpeg_options(&mut cmd, inputs.len(), stream_count, options);
+        cmd.arg(output);
+        ffmpeg_run(cmd, "合并视频", 0, options)
+    }
+
+    fn concat(&self, segments: &[&Path], output: &Path, options: &MuxOptions) -> crate::Result<()> {
+        // concat 解封装器的输入列表, 路径中的单引号需要转义
+        let list_file = PathBuf::from(local::template_dir()).join(format!(
+            "bili-concat-{}-{}.txt",
+            std::process::id(),
+            output.file_stem().unwrap_or_default().to_string_lossy()
+        ));
+        let mut list = String::new();
+        let mut duration = 0;
+        for segment in segments {
+            let segment = std::fs::canonicalize(segment)?;
+            list.push_str(&format!(
+                "file '{}'\n",
+                segment.to_string_lossy().replace('\'', "'\\''")
+            ));
+            duration += ffmpeg_probe(&segment).1;
+        }
+        std::fs::write(&list_file, list)?;
+
+        let mut cmd = ffmpeg_command();
+        cmd.arg("-f").arg("concat");
+        cmd.arg("-safe").arg("0");
+        cmd.arg("-i").arg(&list_file);
+        cmd.arg("-map").arg("0");
+        let stream_count = match (&options.cover, segments.first()) {
+            (Some(_), Some(first)) => ffmpeg_probe(first).0,
+            _ => 0,
+        };
+        ffmpeg_options(&mut cmd, 1, stream_count, options);
+        cmd.arg(output);
+        let result = ffmpeg_run(cmd, "拼接视频", duration, options);
+        let _ = std::fs::remove_file(&list_file);
+        result
+    }
+}
+
+/// ffmpeg命令, 进度输出到stdout
+#[cfg(not(feature = "ffmpeg_api"))]
+fn ffmpeg_command() -> Command {
+    let mut cmd = Command::new(&ffmpeg_info().path);
+    cmd.stdin(Stdio::null());
+    cmd.stderr(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.arg("-y");
+    cmd.arg("-nostats");
+    cmd.arg("-progress");
+    cmd.arg("pipe:1");
+    cmd
+}
+
+/// 只复制不转码, 写入元数据和流的语言,
+/// 封面作为第 input_count 个输入, 输出流序号为 stream_count (前面输入的流数量)
+#[cfg(not(feature = "ffmpeg_api"))]
+fn ffmpeg_options(
+    cmd: &mut Command,
+    input_count: usize,
+    stream_count: usize,
+    options: &MuxOptions,
+) {
+    if let Some(cover) = &options.cover {
+        cmd.arg("-i").arg(cover);
+        cmd.arg("-map").arg(input_count.to_string());
+        cmd.arg(format!("-disposition:{}", stream_count));
+        cmd.arg("attached_pic");
+    }
+    cmd.arg("-c").arg("copy");
+    for (k, v) in &options.metadata {
+        cmd.arg("-metadata").arg(format!("{}={}", k, v));
+    }
+    for (index, language) in &options.languages {
+        cmd.arg(format!("-metadata:s:{}", index));
+        cmd.arg(format!("language={}", language));
+    }
+}
+
+/// 运行ffmpeg并显示进度, duration_us 为0时从ffmpeg输出中解析时长
+#[cfg(not(feature = "ffmpeg_api"))]
+fn ffmpeg_run(
+    mut cmd: Command,
+    title: &str,
+    duration_us: u64,
+    options: &MuxOptions,
+) -> crate::Result<()> {
+    let progress = &options.progress;
+    let mut child = cmd.spawn().with_context(|| "FFMPEG 未能启动")?;
+
+    // stderr 单独线程读取, 避免管道写满阻塞ffmpeg, 同时从中解析输入时长
+    let duration = Arc::new(AtomicU64::new(duration_us));
+    let stderr = child.stderr.take().unwrap();
+    let stderr_duration = duration.clone();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        for line in BufReader::new(stderr).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if let Some(us) = parse_duration_us(&line) {
+                stderr_duration.fetch_max(us, Ordering::Relaxed);
+            }
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        tail
+    });
+
+    let pb = match progress {
+        Some(pb) => pb.clone(),
+        None => {
+            let pb = ProgressBar::new(0);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template(&format!(
+                        "{{spinner:.green}}  {} [{{wide_bar:.cyan/blue}}] {{percent}}% ({{eta}})",
+                        title
+                    ))
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            pb
+        }
+    };
+    // 取消时结束ffmpeg进程, 进程结束后stdout关闭, 下面的读取随之结束
+    let stdout = child.stdout.take().unwrap();
+    let child = Arc::new(Mutex::new(child));
+    let finished = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let child = child.clone();
+        let finished = finished.clone();
+        let cancelled = options.cancelled.clone();
+        std::thread::spawn(move || {
+            while !finished.load(Ordering::Relaxed) {
+                if cancelled.load(Ordering::Relaxed) {
+                    let _ = child.lock().unwrap().kill();
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(200));
+            }
+        })
+    };
+    for line in BufReader::new(stdout).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if let Some(us) = line.strip_prefix("out_time_us=") {
+            if let Ok(us) = us.trim().parse::<u64>() {
+                pb.set_length(duration.load(Ordering::Relaxed).max(us));
+                pb.set_position(us);
+            }
+        } else if line == "progress=end" {
+            break;
+        }
+    }
+    if progress.is_none() {
+        pb.finish_and_clear();
+    }
+    finished.store(true, Ordering::Relaxed);
+    let _ = watcher.join();
+
+    let status = child.lock().unwrap().wait()?;
+    let tail = stderr_thread.join().unwrap_or_default();
+    if options.cancelled.load(Ordering::Relaxed) {
+        return Err(cancelled_error());
+    }
+    if status.success() {
         Ok(())
     } else {
         Err(anyhow::Error::msg(format!(
-            "FFMPEG 未能成功运行 : EXIT CODE : {}",
-            status.code().unwrap()
+            "FFMPEG 未能成功运行 : EXIT CODE : {}\n{}",
+            status
+                .code()
+                .map(|code| code.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            tail.iter().join("\n")
         )))
     }
 }
 
+/// 用 `ffmpeg -i` 探测文件的流数量和时长(微秒), 无法读取时按一个流处理
+#[cfg(not(feature = "ffmpeg_api"))]
+fn ffmpeg_probe(path: &Path) -> (usize, u64) {
+    probe_media(path).unwrap_or((1, 0))
+}
+
+/// 文件的流数量和时长(微秒), ffmpeg无法读取时返回空
+#[cfg(not(feature = "ffmpeg_api"))]
+fn probe_media(path: &Path) -> Option<(usize, u64)> {
+    let output = Command::new(&ffmpeg_info().path)
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .output()
+        .ok()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let streams = stderr
+        .lines()
+        .filter(|line| line.trim_start().starts_with("Stream #0:"))
+        .count();
+    if streams == 0 {
+        return None;
+    }
+    let duration = stderr.lines().find_map(parse_duration_us).unwrap_or(0);
+    Some((streams, duration))
+}
+
+#[cfg(feature = "ffmpeg_api")]
+use ffmpeg_api::probe_media;
+
+/// 检查合并后的文件 : ffmpeg可以读取, 至少包含视频和音频两个流, 并且有时长
+pub(crate) async fn verify_media(path: &Path) -> crate::Result<()> {
+    ffmpeg_check().await?;
+    let (streams, duration) = probe_media(path)
+        .ok_or_else(|| anyhow::Error::msg(format!("无法读取 : {}", path.display())))?;
+    if streams < 2 {
+        return Err(anyhow::Error::msg(format!(
+            "缺少视频或音频 : {}",
+            path.display()
+        )));
+    }
+    if duration == 0 {
+        return Err(anyhow::Error::msg(format!("时长为0 : {}", path.display())));
+    }
+    Ok(())
+}
+
+/// 从ffmpeg输出的 `Duration: 00:01:02.03,` 中解析时长(微秒)
+#[cfg(not(feature = "ffmpeg_api"))]
+fn parse_duration_us(line: &str) -> Option<u64> {
+    let value = line.trim().strip_prefix("Duration: ")?;
+    let value = value.split(',').next()?;
+    let mut parts = value.split(':');
+    let h: f64 = parts.next()?.parse().ok()?;
+    let m: f64 = parts.next()?.parse().ok()?;
+    let s: f64 = parts.next()?.parse().ok()?;
+    Some(((h * 3600.0 + m * 60.0 + s) * 1_000_000.0) as u64)
+}
+
 #[cfg(feature = "ffmpeg_api")]
 mod ffmpeg_api {
-    use anyhow::{anyhow, Context};
+    use super::{cancelled_error, MuxOptions, Muxer};
+    use anyhow::Context;
     use rsmpeg::{
         self,
-        avcodec::{AVCodec, AVCodecContext},
+        avcodec::{AVCodecParameters, AVPacket},
         avformat::{AVFormatContextInput, AVFormatContextOutput},
+        avutil::{av_rescale_q, AVDictionary},
+        ffi::{self, AVRational},
     };
-    use std::collections::HashMap;
     use std::ffi::CString;
-    use std::os::raw::c_int;
-
-    pub fn ffmpeg_merge_files(list: Vec<&str>, output: &str) -> anyhow::Result<()> {
-        let output = CString::new(output)?;
-        let mut output_format_context = AVFormatContextOutput::create(&output, None)?;
-        let mut inputs = vec![];
-        for input in list {
-            let input = CString::new(input).unwrap();
-            let input_format_context = AVFormatContextInput::open(&input)?;
-            let mut stream_index_map = HashMap::new();
-            for av_stream_ref in input_format_context.streams() {
-                let stream_codecpar = av_stream_ref.codecpar();
-                let codec_id = stream_codecpar.codec_id;
-                let decoder = AVCodec::find_decoder(codec_id)
-                    .with_context(|| anyhow!("video decoder ({}) not found.", codec_id))?;
-                let mut decode_context = AVCodecContext::new(&decoder);
-                decode_context.apply_codecpar(&stream_codecpar)?;
-                decode_context.set_time_base(av_stream_ref.time_base);
-                if let Some(framerate) = av_stream_ref.guess_framerate() {
-                    decode_context.set_framerate(framerate);
-                }
-                let mut out_stream = output_format_context.new_stream();
-                out_stream.set_codecpar(decode_context.extract_codecpar());
-                out_stream.set_time_base(decode_context.time_base);
-                stream_index_map.insert(av_stream_ref.index as i32, out_stream.index as i32);
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+
+    const TIME_BASE_Q: AVRational = AVRational {
+        num: 1,
+        den: ffi::AV_TIME_BASE as i32,
+    };
+
+    /// 进程内调用libav
+    pub(super) struct ApiMuxer;
+
+    impl Muxer for ApiMuxer {
+        fn merge(
+            &self,
+            inputs: &[&Path],
+            output: &Path,
+            options: &MuxOptions,
+        ) -> crate::Result<()> {
+            let mut output_format_context = create_output(output)?;
+            let mut opened = vec![];
+            for input in inputs {
+                let input_format_context = open_input(input)?;
+                let stream_index_map = copy_streams(
+                    &input_format_context,
+                    &mut output_format_context,
+                    options,
+                    0,
+                )?;
+                opened.push((input_format_context, stream_index_map));
+            }
+            let cover = add_cover(&mut output_format_context, options)?;
+            apply_metadata(&mut output_format_context, options)?;
+
+            let mut dict = None;
+            output_format_context.write_header(&mut dict)?;
+            let out_time_bases = time_bases_of_output(&output_format_context);
+
+            if let Some(pb) = &options.progress {
+                let duration = opened.iter().map(|(x, _)| x.duration).max().unwrap_or(0);
+                pb.set_length(duration.max(0) as u64);
+            }
+
+            // 每个输入保留一个待写入的包, 总是先写dts最小的, 保证交错写入
+            let mut pending: Vec<Option<AVPacket>> = vec![];
+            for (input_format_context, _) in opened.iter_mut() {
+                pending.push(input_format_context.read_packet()?);
             }
-            inputs.push((input_format_context, stream_index_map));
-        }
-        let mut dict = None;
-        output_format_context.write_header(&mut dict)?;
-        for (mut input_format_context, stream_index_map) in inputs {
             loop {
-                let mut packet = match input_format_context.read_packet()? {
-                    Some(x) => x,
+                if options.cancelled.load(Ordering::Relaxed) {
+                    return Err(cancelled_error());
+                }
+                let mut next: Option<(usize, i64)> = None;
+                for (i, packet) in pending.iter().enumerate() {
+                    if let Some(packet) = packet {
+                        let in_tb = time_base_of_input(&opened[i].0, packet.stream_index);
+                        let dts = av_rescale_q(dts_of(packet), in_tb, TIME_BASE_Q);
+                        if next.map(|(_, x)| dts < x).unwrap_or(true) {
+                            next = Some((i, dts));
+                        }
+                    }
+                }
+                let i = match next {
+                    Some((i, dts)) => {
+                        if let Some(pb) = &options.progress {
+                            pb.set_position(dts.max(0) as u64);
+                        }
+                        i
+                    }
                     None => break,
                 };
-                packet.set_stream_index(
-                    stream_index_map
-                        .get(&(packet.stream_index as i32))
-                        .unwrap()
-                        .clone() as c_int,
-                );
-                output_format_context
-                    .interleaved_write_frame(&mut packet)
-                    .unwrap();
+                let mut packet = pending[i].take().unwrap();
+                let (input_format_context, stream_index_map) = &mut opened[i];
+                let in_tb = time_base_of_input(input_format_context, packet.stream_index);
+                let out_index = stream_index_map[packet.stream_index as usize];
+                packet.rescale_ts(in_tb, out_time_bases[out_index as usize]);
+                packet.set_stream_index(out_index);
+                packet.set_pos(-1);
+                output_format_context.interleaved_write_frame(&mut packet)?;
+                pending[i] = input_format_context.read_packet()?;
+            }
+            write_cover(&mut output_format_context, cover, &out_time_bases)?;
+            output_format_context.write_trailer()?;
+            Ok(())
+        }
+
+        fn concat(
+            &self,
+            segments: &[&Path],
+            output: &Path,
+            options: &MuxOptions,
+        ) -> crate::Result<()> {
+            let first = match segments.first() {
+                Some(first) => first,
+                None => return Err(anyhow::Error::msg("没有需要拼接的分段")),
+            };
+            let mut output_format_context = create_output(output)?;
+            let stream_index_map =
+                copy_streams(&open_input(first)?, &mut output_format_context, options, 0)?;
+            let cover = add_cover(&mut output_format_context, options)?;
+            apply_metadata(&mut output_format_context, options)?;
+
+            let mut dict = None;
+            output_format_context.write_header(&mut dict)?;
+            let out_time_bases = time_bases_of_output(&output_format_context);
+
+            // 每个输出流的时间偏移, 上一段结束的位置就是下一段开始的位置
+            let mut offsets = vec![0i64; out_time_bases.len()];
+            let mut ends = vec![0i64; out_time_bases.len()];
+            for segment in segments {
+                let mut input_format_context = open_input(segment)?;
+                // 每段的起始时间戳不一定为0, 以第一个包为准对齐
+                let mut starts: Vec<Option<i64>> = vec![None; out_time_bases.len()];
+                while let Some(mut packet) = input_format_context.read_packet()? {
+                    if options.cancelled.load(Ordering::Relaxed) {
+                        return Err(cancelled_error());
+                    }
+                    let out_index = match stream_index_map.get(packet.stream_index as usize) {
+                        Some(x) => *x as usize,
+                        None => continue,
+                    };
+                    let in_tb = time_base_of_input(&input_format_context, packet.stream_index);
+                    packet.rescale_ts(in_tb, out_time_bases[out_index]);
+                    let start = *starts[out_index].get_or_insert(dts_of(&packet));
+                    let shift = offsets[out_index] - start;
+                    if packet.pts != ffi::AV_NOPTS_VALUE {
+                        packet.set_pts(packet.pts + shift);
+                    }
+                    if packet.dts != ffi::AV_NOPTS_VALUE {
+                        packet.set_dts(packet.dts + shift);
+                    }
+                    ends[out_index] = ends[out_index].max(dts_of(&packet) + packet.duration);
+                    packet.set_stream_index(out_index as i32);
+                    packet.set_pos(-1);
+                    output_format_context.interleaved_write_frame(&mut packet)?;
+                }
+                offsets.clone_from(&ends);
+            }
+            write_cover(&mut output_format_context, cover, &out_time_bases)?;
+            output_format_context.write_trailer()?;
+            Ok(())
+        }
+    }
+
+    /// 文件的流数量和时长(微秒), 无法打开时返回空
+    pub(super) fn probe_media(path: &Path) -> Option<(usize, u64)> {
+        let input_format_context = open_input(path).ok()?;
+        Some((
+            input_format_context.streams().len(),
+            input_format_context.duration.max(0) as u64,
+        ))
+    }
+
+    fn c_path(path: &Path) -> crate::Result<CString> {
+        Ok(CString::new(path.to_string_lossy().as_bytes())?)
+    }
+
+    fn create_output(output: &Path) -> crate::Result<AVFormatContextOutput> {
+        Ok(AVFormatContextOutput::create(&c_path(output)?, None)?)
+    }
+
+    fn open_input(input: &Path) -> crate::Result<AVFormatContextInput> {
+        AVFormatContextInput::open(&c_path(input)?)
+            .with_context(|| format!("无法打开 : {}", input.display()))
+    }
+
+    /// 按输入的流创建输出流并设置语言, 返回 输入流序号 -> 输出流序号
+    fn copy_streams(
+        input_format_context: &AVFormatContextInput,
+        output_format_context: &mut AVFormatContextOutput,
+        options: &MuxOptions,
+        disposition: i32,
+    ) -> crate::Result<Vec<i32>> {
+        let mut stream_index_map = vec![];
+        for av_stream_ref in input_format_context.streams() {
+            let mut codecpar = AVCodecParameters::new();
+            codecpar.copy(&av_stream_ref.codecpar());
+            // 复制流时codec_tag可能与输出格式不兼容, 交给muxer重新选择
+            unsafe {
+                (*codecpar.as_mut_ptr()).codec_tag = 0;
+            }
+            let mut out_stream = output_format_context.new_stream();
+            out_stream.set_codecpar(codecpar);
+            out_stream.set_time_base(av_stream_ref.time_base);
+            unsafe {
+                (*out_stream.as_mut_ptr()).disposition = disposition;
+            }
+            let index = out_stream.index;
+            if let Some((_, language)) =
+                options.languages.iter().find(|(x, _)| *x == index as usize)
+            {
+                out_stream.set_metadata(dictionary(&[("language", language.as_str())])?);
+            }
+            stream_index_map.push(index);
+        }
+        Ok(stream_index_map)
+    }
+
+    /// 创建封面流, 封面的数据包要在写入header之后写入, 所以返回封面输入和输出流序号
+    fn add_cover(
+        output_format_context: &mut AVFormatContextOutput,
+        options: &MuxOptions,
+    ) -> crate::Result<Option<(AVFormatContextInput, i32)>> {
+        let cover = match &options.cover {
+            Some(cover) => cover,
+            None => return Ok(None),
+        };
+        let input_format_context = open_input(cover)?;
+        let index = copy_streams(
+            &input_format_context,
+            output_format_context,
+            options,
+            ffi::AV_DISPOSITION_ATTACHED_PIC as i32,
+        )?
+        .first()
+        .copied()
+        .with_context(|| format!("封面中没有图片 : {}", cover.display()))?;
+        Ok(Some((input_format_context, index)))
+    }
+
+    fn write_cover(
+        output_format_context: &mut AVFormatContextOutput,
+        cover: Option<(AVFormatContextInput, i32)>,
+        out_time_bases: &[AVRational],
+    ) -> crate::Result<()> {
+        if let Some((mut input_format_context, index)) = cover {
+            if let Some(mut packet) = input_format_context.read_packet()? {
+                let in_tb = time_base_of_input(&input_format_context, packet.stream_index);
+                packet.rescale_ts(in_tb, out_time_bases[index as usize]);
+                packet.set_stream_index(index);
+                packet.set_pos(-1);
+                output_format_context.interleaved_write_frame(&mut packet)?;
             }
         }
-        output_format_context.write_trailer()?;
         Ok(())
     }
+
+    fn time_base_of_input(input_format_context: &AVFormatContextInput, index: i32) -> AVRational {
+        input_format_context
+            .streams()
+            .get(index as usize)
+            .map(|x| x.time_base)
+            .unwrap_or(TIME_BASE_Q)
+    }
+
+    /// write_header之后muxer可能调整了输出流的time_base, 必须之后再取
+    fn time_bases_of_output(output_format_context: &AVFormatContextOutput) -> Vec<AVRational> {
+        output_format_context
+            .streams()
+            .into_iter()
+            .map(|x| x.time_base)
+            .collect()
+    }
+
+    fn dts_of(packet: &AVPacket) -> i64 {
+        if packet.dts != ffi::AV_NOPTS_VALUE {
+            packet.dts
+        } else if packet.pts != ffi::AV_NOPTS_VALUE {
+            packet.pts
+        } else {
+            0
+        }
+    }
+
+    fn dictionary(entries: &[(&str, &str)]) -> crate::Result<Option<AVDictionary>> {
+        let mut dict: Option<AVDictionary> = None;
+        for (k, v) in entries {
+            let k = CString::new(*k)?;
+            let v = CString::new(*v)?;
+            dict = Some(match dict {
+                Some(dict) => dict.set(&k, &v, 0),
+                None => AVDictionary::new(&k, &v, 0),
+            });
+        }
+        Ok(dict)
+    }
+
+    fn apply_metadata(
+        output_format_context: &mut AVFormatContextOutput,
+        options: &MuxOptions,
+    ) -> crate::Result<()> {
+        let entries: Vec<(&str, &str)> = options
+            .metadata
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        if let Some(dict) = dictionary(&entries)? {
+            output_format_context.set_metadata(Some(dict));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_line() {
+        assert_eq!(
+            parse_duration_us("  Duration: 00:01:02.50, start: 0.000000, bitrate: 1000 kb/s"),
+            Some(62_500_000)
+        );
+        assert_eq!(
+            parse_duration_us("Duration: 01:00:00.00, start: 0.000000"),
+            Some(3_600_000_000)
+        );
+    }
+
+    #[cfg(all(unix, not(feature = "ffmpeg_api")))]
+    #[test]
+    fn cancel_kills_process() {
+        let mut cmd = Command::new("sleep");
+        cmd.arg("30").stdout(Stdio::piped()).stderr(Stdio::piped());
+        let options = MuxOptions {
+            progress: Some(ProgressBar::hidden()),
+            ..Default::default()
+        };
+        let cancelled = options.cancelled.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            cancelled.store(true, Ordering::Relaxed);
+        });
+        let start = std::time::Instant::now();
+        assert!(ffmpeg_run(cmd, "test", 0, &options).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(not(feature = "ffmpeg_api"))]
+    #[test]
+    fn cover_and_language_arguments() {
+        let mut cmd = Command::new("ffmpeg");
+        let options = MuxOptions {
+            cover: Some(PathBuf::from("a.cover.jpg")),
+            languages: vec![(1, "jpn".to_owned())],
+            ..Default::default()
+        };
+        ffmpeg_options(&mut cmd, 2, 2, &options);
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|x| x.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            args,
+            [
+                "-i",
+                "a.cover.jpg",
+                "-map",
+                "2",
+                "-disposition:2",
+                "attached_pic",
+                "-c",
+                "copy",
+                "-metadata:s:1",
+                "language=jpn",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_duration_other_lines() {
+        assert_eq!(parse_duration_us("  Duration: N/A, bitrate: N/A"), None);
+        assert_eq!(parse_duration_us("Stream #0:0: Video: h264"), None);
+    }
 }
@@ -0,0 +1,119 @@
+use crate::{api, resolver};
+use console::{style, Emoji};
+
+const XOR_CODE: i64 = 23442827791579;
+const MASK_CODE: i64 = 2251799813685247;
+const MAX_AID: i64 = 1 << 51;
+const TABLE: &[u8; 58] = b"FcwAPNKTMug3GV5Lj7EJnHpWsx4tb8haYeviqBz6rkCy12mUSDQX9RdoZf";
+
+/// av号转BV号, av号需要在 1 到 2^51 之间
+pub(crate) fn av_to_bv(aid: i64) -> crate::Result<String> {
+    if aid <= 0 || aid >= MAX_AID {
+        return Err(anyhow::Error::msg(format!("不是有效的av号 : av{}", aid)));
+    }
+    let mut bytes = *b"BV1000000000";
+    let mut tmp = (MAX_AID | aid) ^ XOR_CODE;
+    let mut i = bytes.len() - 1;
+    while tmp > 0 {
+        bytes[i] = TABLE[(tmp % 58) as usize];
+        tmp /= 58;
+        i -= 1;
+    }
+    bytes.swap(3, 9);
+    bytes.swap(4, 7);
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// BV号转av号
+pub(crate) fn bv_to_av(bvid: &str) -> crate::Result<i64> {
+    let mut bytes = bvid.as_bytes().to_vec();
+    if bytes.len() != 12 || !bvid.starts_with("BV1") {
+        return Err(anyhow::Error::msg(format!("不是有效的BV号 : {}", bvid)));
+    }
+    bytes.swap(3, 9);
+    bytes.swap(4, 7);
+    let mut tmp: i64 = 0;
+    for b in &bytes[3..] {
+        let index = TABLE
+            .iter()
+            .position(|x| x == b)
+            .ok_or_else(|| anyhow::Error::msg(format!("不是有效的BV号 : {}", bvid)))?;
+        tmp = tmp * 58 + index as i64;
+    }
+    Ok((tmp & MASK_CODE) ^ XOR_CODE)
+}
+
+/// 打印输入对应的 av / BV / cid / ep / ss, 也可以输入md
+pub(crate) async fn print_ids(input: &str) -> crate::Result<()> {
+    let input = resolver::resolve_short_url(input.to_owned()).await?;
+    println!();
+    if let Some(bvid) = resolver::find_bv(&input)? {
+        let view = api::video_view(&bvid).await?;
+        println!("{}{}", Emoji("✨", ""), style(&view.title).bold());
+        println!("  av  : av{}", bv_to_av(&bvid)?);
+        println!("  BV  : {}", bvid);
+        println!("  cid : {}", view.cid);
+        if let Some(ep_id) = view.ep_id {
+            let season = api::bangumi_season(&format!("ep_id={}", ep_id)).await?;
+            println!("  ep  : ep{}", ep_id);
+            println!("  ss  : ss{}", season.season_id);
+        }
+        return Ok(());
+    }
+    if let Some(query) = resolver::find_ep_or_ss(&input).await? {
+        let season = api::bangumi_season(&query).await?;
+        println!("{}{}", Emoji("✨", ""), style(&season.title).bold());
+        println!("  ss  : ss{}", season.season_id);
+        for ep in season.episodes {
+            let line = format!(
+                "  ep{} av{} {} cid {} {}",
+                ep.ep_id, ep.aid, ep.bvid, ep.cid, ep.title
+            );
+            if query == format!("ep_id={}", ep.ep_id) {
+                println!("{}", style(line).cyan());
+            } else {
+                println!("{}", line);
+            }
+        }
+        return Ok(());
+    }
+    Err(anyhow::Error::msg(format!("无法识别 : {}", input)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn av_to_bv_known_ids() {
+        assert_eq!(av_to_bv(170001).unwrap(), "BV17x411w7KC");
+        assert_eq!(av_to_bv(111298867365120).unwrap(), "BV1L9Uoa9EUx");
+    }
+
+    #[test]
+    fn bv_to_av_known_ids() {
+        assert_eq!(bv_to_av("BV17x411w7KC").unwrap(), 170001);
+        assert_eq!(bv_to_av("BV1L9Uoa9EUx").unwrap(), 111298867365120);
+    }
+
+    #[test]
+    fn round_trip() {
+        for aid in [1, 2, 170001, 99999999, 1 << 40, MAX_AID - 1] {
+            assert_eq!(bv_to_av(&av_to_bv(aid).unwrap()).unwrap(), aid);
+        }
+    }
+
+    #[test]
+    fn av_out_of_range() {
+        assert!(av_to_bv(0).is_err());
+        assert!(av_to_bv(-1).is_err());
+        assert!(av_to_bv(MAX_AID).is_err());
+    }
+
+    #[test]
+    fn invalid_bv() {
+        assert!(bv_to_av("BV17x411w7K").is_err());
+        assert!(bv_to_av("AV17x411w7KC").is_err());
+        assert!(bv_to_av("BV17x411w7K0").is_err());
+    }
+}
@@ -5,10 +5,11 @@ use std::time::Duration;
 
 use async_once::AsyncOnce;
 use lazy_static::lazy_static;
-use sea_orm::{ActiveModelTrait, EntityTrait, Set};
-use sea_orm::{ConnectionTrait, DatabaseConnection, Schema, Statement};
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, QueryOrder, Set};
+use sea_orm::{ConnectionTrait, DatabaseConnection, Schema, Statement, TransactionTrait};
 use tokio::sync::Mutex;
 
+use crate::download::DownloadItem;
 use crate::entities::*;
 use anyhow::Result;
 
@@ -28,7 +29,7 @@ pub(crate) fn join_paths<P: AsRef<Path>>(paths: Vec<P>) -> String {
 
 /// 取配置文件目录
 #[cfg(target_os = "macos")]
-fn cfg_local_dir() -> String {
+pub(crate) fn cfg_local_dir() -> String {
     join_paths(vec![
         dirs::home_dir().unwrap().to_str().unwrap(),
         "Library",
@@ -39,7 +40,7 @@ fn cfg_local_dir() -> String {
 
 /// 取配置文件目录
 #[cfg(target_os = "windows")]
-fn cfg_local_dir() -> String {
+pub(crate) fn cfg_local_dir() -> String {
     join_paths(vec![
         dirs::home_dir().unwrap().to_str().unwrap(),
         "AppData",
@@ -50,11 +51,8 @@ fn cfg_local_dir() -> String {
 
 /// 取配置文件目录
 #[cfg(target_os = "linux")]
-fn cfg_local_dir() -> String {
-    join_paths(vec![
-        dirs::home_dir().unwrap().to_str().unwrap(),
-        ".bili",
-    ])
+pub(crate) fn cfg_local_dir() -> String {
+    join_paths(vec![dirs::home_dir().unwrap().to_str().unwrap(), ".bili"])
 }
 
 /// 取临时文件目录
@@ -75,9 +73,24 @@ pub(crate) fn template_dir() -> String {
     std::env::temp_dir().to_str().unwrap().to_owned()
 }
 
-/// 初始化配置文件目录
+/// 初始化配置文件目录, 只有当前用户可以访问
 fn init_dir() {
     std::fs::create_dir_all(cfg_local_dir()).unwrap();
+    restrict_permissions(&cfg_local_dir(), 0o700).unwrap();
+}
+
+/// 限制文件权限, 例如 0o600 只有当前用户可以读写
+#[cfg(unix)]
+pub(crate) fn restrict_permissions(path: &str, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+/// 限制文件权限, 非unix系统沿用默认权限
+#[cfg(not(unix))]
+pub(crate) fn restrict_permissions(_path: &str, _mode: u32) -> Result<()> {
+    Ok(())
 }
 
 /// 连接到Sqlite数据库
@@ -93,17 +106,19 @@ pub(crate) async fn connect_db(path: &str) -> DatabaseConnection {
 }
 
 /// 如果表不存在则创建
-pub(crate) async fn create_table_if_not_exists<E>(db: &DatabaseConnection, entity: E)
-    where
-        E: EntityTrait,
+pub(crate) async fn create_table_if_not_exists<C, E>(db: &C, entity: E) -> Result<()>
+where
+    C: ConnectionTrait,
+    E: EntityTrait,
 {
-    if !has_table(db, entity.table_name()).await {
-        create_table(db, entity).await;
+    if !has_table(db, entity.table_name()).await? {
+        create_table(db, entity).await?;
     };
+    Ok(())
 }
 
 /// 是否存在表
-async fn has_table(db: &DatabaseConnection, table_name: &str) -> bool {
+async fn has_table<C: ConnectionTrait>(db: &C, table_name: &str) -> Result<bool> {
     let stmt = Statement::from_string(
         db.get_database_backend(),
         format!(
@@ -111,29 +126,33 @@ async fn has_table(db: &DatabaseConnection, table_name: &str) -> bool {
             table_name,
         ),
     );
-    let rsp = db.query_one(stmt).await.unwrap().unwrap();
-    let count: i64 = rsp.try_get("", "c").unwrap();
-    count > 0
+    let count: i64 = match db.query_one(stmt).await? {
+        Some(rsp) => rsp.try_get("", "c")?,
+        None => 0,
+    };
+    Ok(count > 0)
 }
 
 /// 创建表
-async fn create_table<E>(db: &DatabaseConnection, entity: E)
-    where
-        E: EntityTrait,
+async fn create_table<C, E>(db: &C, entity: E) -> Result<()>
+where
+    C: ConnectionTrait,
+    E: EntityTrait,
 {
     let builder = db.get_database_backend();
     let schema = Schema::new(builder);
     let stmt = &schema.create_table_from_entity(entity);
     let stmt = builder.build(stmt);
-    db.execute(stmt).await.unwrap();
+    db.execute(stmt).await?;
+    Ok(())
 }
 
 /// 索引是否存在
-pub(crate) async fn index_exists(
-    db: &DatabaseConnection,
+pub(crate) async fn index_exists<C: ConnectionTrait>(
+    db: &C,
     table_name: &str,
     index_name: &str,
-) -> bool {
+) -> Result<bool> {
     let stmt = Statement::from_string(
         db.get_database_backend(),
         format!(
@@ -141,22 +160,40 @@ pub(crate) async fn index_exists(
             table_name, index_name,
         ),
     );
-    db.query_one(stmt)
-        .await
-        .unwrap()
-        .unwrap()
-        .try_get::<i64>("", "c")
-        .unwrap()
-        > 0
+    let count: i64 = match db.query_one(stmt).await? {
+        Some(rsp) => rsp.try_get("", "c")?,
+        None => 0,
+    };
+    Ok(count > 0)
+}
+
+/// 表中是否有这一列
+pub(crate) async fn column_exists<C: ConnectionTrait>(
+    db: &C,
+    table_name: &str,
+    column_name: &str,
+) -> Result<bool> {
+    let stmt = Statement::from_string(
+        db.get_database_backend(),
+        format!(
+            "select COUNT(*) AS c from pragma_table_info('{}') where name='{}';",
+            table_name, column_name,
+        ),
+    );
+    let count: i64 = match db.query_one(stmt).await? {
+        Some(rsp) => rsp.try_get("", "c")?,
+        None => 0,
+    };
+    Ok(count > 0)
 }
 
 /// 创建索引
-pub(crate) async fn create_index(
-    db: &DatabaseConnection,
+pub(crate) async fn create_index<C: ConnectionTrait>(
+    db: &C,
     table_name: &str,
     columns: Vec<&str>,
     index_name: &str,
-) {
+) -> Result<()> {
     let stmt = Statement::from_string(
         db.get_database_backend(),
         format!(
@@ -166,28 +203,30 @@ pub(crate) async fn create_index(
             columns.join(","),
         ),
     );
-    db.execute(stmt).await.unwrap();
+    db.execute(stmt).await?;
+    Ok(())
+}
+
+/// 配置文件数据库的路径
+pub(crate) fn properties_db_path() -> String {
+    join_paths(vec![cfg_local_dir().as_str(), "properties.db"])
 }
 
 lazy_static! {
-    /// 静态初始化配置文件数据库
+    /// 静态初始化配置文件数据库, 表结构由 migration 在启动时创建和升级
     pub(crate) static ref PROPERTY_DB: AsyncOnce<Mutex<DatabaseConnection>> =
         AsyncOnce::new(async {
             init_dir();
-            let db =
-                connect_db(join_paths(vec![cfg_local_dir().as_str(), "properties.db"]).as_str())
-                    .await;
-            create_table_if_not_exists(&db, property::Entity).await;
-            property::init_indexes(&db).await;
+            let path = properties_db_path();
+            let db = connect_db(path.as_str()).await;
+            restrict_permissions(path.as_str(), 0o600).unwrap();
             Mutex::<DatabaseConnection>::new(db)
         });
 }
 
 /// 从数据库读取配置文件
-pub(crate) async fn load_property_from_db(db: &DatabaseConnection, k: String) -> Result<String> {
-    let in_db = property::Entity::find_by_id(k.clone())
-        .one(db.deref())
-        .await?;
+pub(crate) async fn load_property_from_db<C: ConnectionTrait>(db: &C, k: String) -> Result<String> {
+    let in_db = property::Entity::find_by_id(k.clone()).one(db).await?;
     Ok(match in_db {
         Some(in_db) => in_db.v,
         None => String::default(),
@@ -200,20 +239,18 @@ pub(crate) async fn load_property(k: String) -> Result<String> {
 }
 
 /// 写入配置文件夹
-pub(crate) async fn save_property_from_db(
-    db: &DatabaseConnection,
+pub(crate) async fn save_property_from_db<C: ConnectionTrait>(
+    db: &C,
     k: String,
     v: String,
 ) -> Result<()> {
-    let in_db = property::Entity::find_by_id(k.clone())
-        .one(db.deref())
-        .await?;
+    let in_db = property::Entity::find_by_id(k.clone()).one(db).await?;
     match in_db {
         Some(in_db) => {
             let mut data: property::ActiveModel = in_db.into();
             data.k = Set(k.clone());
             data.v = Set(v.clone());
-            data.update(db.deref()).await?;
+            data.update(db).await?;
         }
         None => {
             let insert = property::ActiveModel {
@@ -221,7 +258,7 @@ pub(crate) async fn save_property_from_db(
                 v: Set(v.clone()),
                 ..Default::default()
             };
-            insert.insert(db.deref()).await?;
+            insert.insert(db).await?;
         }
     };
     Ok(())
@@ -232,6 +269,193 @@ pub(crate) async fn save_property(k: String, v: String) -> Result<()> {
     save_property_from_db(PROPERTY_DB.get().await.lock().await.deref(), k, v).await
 }
 
+/// 在一个事务中写入多个配置项, 值为None时删除
+pub(crate) async fn update_properties(changes: Vec<(String, Option<String>)>) -> Result<()> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    let txn = db.begin().await?;
+    for (k, v) in changes {
+        match v {
+            Some(v) => save_property_from_db(&txn, k, v).await?,
+            None => {
+                property::Entity::delete_by_id(k).exec(&txn).await?;
+            }
+        }
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+/// 以prefix开头的配置项
+pub(crate) async fn list_property_keys(prefix: &str) -> Result<Vec<String>> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    Ok(property::Entity::find()
+        .filter(property::Column::K.starts_with(prefix))
+        .all(db.deref())
+        .await?
+        .into_iter()
+        .map(|x| x.k)
+        .collect())
+}
+
+/// 删除配置项
+pub(crate) async fn remove_property(k: String) -> Result<()> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    property::Entity::delete_by_id(k).exec(db.deref()).await?;
+    Ok(())
+}
+
+/// 当前时间戳(秒)
+pub(crate) fn now_timestamp() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// 列出全部订阅
+pub(crate) async fn list_subscriptions() -> Result<Vec<subscription::Model>> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    Ok(subscription::Entity::find().all(db.deref()).await?)
+}
+
+/// 添加订阅, 已存在相同网址时返回已有的订阅
+pub(crate) async fn add_subscription(url: String, title: String) -> Result<subscription::Model> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    let in_db = subscription::Entity::find()
+        .filter(subscription::Column::Url.eq(url.clone()))
+        .one(db.deref())
+        .await?;
+    if let Some(in_db) = in_db {
+        return Ok(in_db);
+    }
+    let insert = subscription::ActiveModel {
+        url: Set(url),
+        title: Set(title),
+        created_at: Set(now_timestamp()),
+        last_sync: Set(0),
+        ..Default::default()
+    };
+    Ok(insert.insert(db.deref()).await?)
+}
+
+/// 删除订阅, 返回是否存在
+pub(crate) async fn remove_subscription(id: i64) -> Result<bool> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    let rsp = subscription::Entity::delete_by_id(id)
+        .exec(db.deref())
+        .await?;
+    Ok(rsp.rows_affected > 0)
+}
+
+/// 记录订阅的同步时间
+pub(crate) async fn touch_subscription(id: i64) -> Result<()> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    if let Some(in_db) = subscription::Entity::find_by_id(id).one(db.deref()).await? {
+        let mut data: subscription::ActiveModel = in_db.into();
+        data.last_sync = Set(now_timestamp());
+        data.update(db.deref()).await?;
+    }
+    Ok(())
+}
+
+/// 是否已经下载过
+pub(crate) async fn archive_contains(bvid: &str) -> Result<bool> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    Ok(archive::Entity::find_by_id(bvid.to_owned())
+        .one(db.deref())
+        .await?
+        .is_some())
+}
+
+/// 记录下载过的视频
+pub(crate) async fn save_archive(bvid: String, title: String, file: String) -> Result<()> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    if archive::Entity::find_by_id(bvid.clone())
+        .one(db.deref())
+        .await?
+        .is_some()
+    {
+        return Ok(());
+    }
+    let insert = archive::ActiveModel {
+        bvid: Set(bvid),
+        title: Set(title),
+        file: Set(file),
+        created_at: Set(now_timestamp()),
+    };
+    insert.insert(db.deref()).await?;
+    Ok(())
+}
+
+/// 保存接口提交的任务, 状态为 waiting
+pub(crate) async fn add_serve_job(
+    url: String,
+    item: &DownloadItem,
+    quality: Option<i64>,
+    format: String,
+    audio_quality: Option<i64>,
+) -> Result<serve_job::Model> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    let now = now_timestamp();
+    let insert = serve_job::ActiveModel {
+        url: Set(url),
+        bvid: Set(item.bvid.clone()),
+        cid: Set(item.cid),
+        title: Set(item.title.clone()),
+        folder: Set(item.folder.to_string_lossy().into_owned()),
+        name: Set(item.name.clone()),
+        quality: Set(quality),
+        format: Set(format),
+        audio_quality: Set(audio_quality),
+        state: Set("waiting".to_owned()),
+        message: Set(String::default()),
+        created_at: Set(now),
+        updated_at: Set(now),
+        ..Default::default()
+    };
+    Ok(insert.insert(db.deref()).await?)
+}
+
+/// 更新任务的状态
+pub(crate) async fn update_serve_job(id: i64, state: String, message: String) -> Result<()> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    if let Some(in_db) = serve_job::Entity::find_by_id(id).one(db.deref()).await? {
+        let mut data: serve_job::ActiveModel = in_db.into();
+        data.state = Set(state);
+        data.message = Set(message);
+        data.updated_at = Set(now_timestamp());
+        data.update(db.deref()).await?;
+    }
+    Ok(())
+}
+
+/// 全部保存的任务, 按提交顺序
+pub(crate) async fn list_serve_jobs() -> Result<Vec<serve_job::Model>> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    Ok(serve_job::Entity::find()
+        .order_by_asc(serve_job::Column::Id)
+        .all(db.deref())
+        .await?)
+}
+
+/// 删除在 before 之前结束的任务, 返回删除的任务
+pub(crate) async fn remove_serve_jobs_before(states: &[&str], before: i64) -> Result<Vec<i64>> {
+    let db = PROPERTY_DB.get().await.lock().await;
+    let ids: Vec<i64> = serve_job::Entity::find()
+        .filter(serve_job::Column::State.is_in(states.iter().copied()))
+        .filter(serve_job::Column::UpdatedAt.lt(before))
+        .all(db.deref())
+        .await?
+        .into_iter()
+        .map(|x| x.id)
+        .collect();
+    serve_job::Entity::delete_many()
+        .filter(serve_job::Column::Id.is_in(ids.clone()))
+        .exec(db.deref())
+        .await?;
+    Ok(ids)
+}
+
 pub(crate) fn allowed_file_name(title: &str) -> String {
     title
         .replace("#", "_")
@@ -250,4 +474,4 @@ pub(crate) fn allowed_file_name(title: &str) -> String {
 
 pub(crate) fn current_exe_directory() -> PathBuf {
     env::current_exe().unwrap().parent().unwrap().to_path_buf()
-}
\ No newline at end of file
+}
@@ -2,27 +2,33 @@ use std::process::exit;
 
 pub(crate) use anyhow::Result;
 
+mod api;
+mod batch;
 mod cli;
+mod config;
 mod download;
 mod entities;
+mod fav;
 mod ffmpeg;
+mod id;
 mod local;
+mod migration;
+mod profile;
+mod queue;
+mod resolver;
+mod secret;
+mod serve;
+mod subscribe;
+mod tui;
 mod user;
 
 #[tokio::main]
 async fn main() {
-    if let Err(e) = ffmpeg::ffmpeg_run_version() {
-        if cfg!(debug_assertions) {
-            panic!("{}", e);
-        }
-        println!("{}", e);
-        exit(0);
-    }
     if let Err(e) = cli::run().await {
         if cfg!(debug_assertions) {
             panic!("{}", e);
         }
-        println!("{}", e);
-        exit(0);
+        eprintln!("{}", e);
+        exit(1);
     }
 }
@@ -0,0 +1,271 @@
+use std::ops::Deref;
+
+use console::{style, Emoji};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DatabaseTransaction, EntityTrait, Statement,
+    TransactionTrait,
+};
+
+use crate::entities::*;
+use crate::{cli, local, profile, user};
+
+/// 数据库结构的一次变更, version 从1开始递增, 已经发布的迁移不能修改
+/// 每个迁移和它的版本记录在同一个事务中执行, 失败时整体回滚
+struct Migration {
+    version: i64,
+    name: &'static str,
+    up: fn(&DatabaseTransaction) -> BoxFuture<'_, crate::Result<()>>,
+}
+
+/// 全部迁移, 按版本排列, 新的迁移添加在末尾
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "创建配置, 订阅, 下载记录表",
+        up: initial_tables,
+    },
+    Migration {
+        version: 2,
+        name: "默认账号的配置项移到 profile.default",
+        up: default_profile_keys,
+    },
+    Migration {
+        version: 3,
+        name: "创建接口任务表",
+        up: serve_job_table,
+    },
+    Migration {
+        version: 4,
+        name: "接口任务添加下载格式和音质",
+        up: serve_job_format,
+    },
+];
+
+/// 第一个版本的表, 已有的数据库中表已经存在时跳过
+fn initial_tables(db: &DatabaseTransaction) -> BoxFuture<'_, crate::Result<()>> {
+    async move {
+        local::create_table_if_not_exists(db, property::Entity).await?;
+        property::init_indexes(db).await?;
+        local::create_table_if_not_exists(db, subscription::Entity).await?;
+        local::create_table_if_not_exists(db, archive::Entity).await?;
+        Ok(())
+    }
+    .boxed()
+}
+
+/// default 账号原来使用不带前缀的配置项, 与全局配置项混在一起
+fn default_profile_keys(db: &DatabaseTransaction) -> BoxFuture<'_, crate::Result<()>> {
+    async move {
+        for key in [profile::WEB_TOKEN_KEY, user::WEB_TOKEN_CHECKED_PROPERTY] {
+            let value = local::load_property_from_db(db, key.to_owned()).await?;
+            if value.is_empty() {
+                continue;
+            }
+            let new_key = profile::profile_key(profile::DEFAULT_PROFILE, key);
+            if local::load_property_from_db(db, new_key.clone())
+                .await?
+                .is_empty()
+            {
+                local::save_property_from_db(db, new_key, value).await?;
+            }
+            property::Entity::delete_by_id(key.to_owned())
+                .exec(db)
+                .await?;
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+fn serve_job_table(db: &DatabaseTransaction) -> BoxFuture<'_, crate::Result<()>> {
+    async move { local::create_table_if_not_exists(db, serve_job::Entity).await }.boxed()
+}
+
+/// 新建的数据库在版本3已经按最新的结构建表, 列存在时跳过
+fn serve_job_format(db: &DatabaseTransaction) -> BoxFuture<'_, crate::Result<()>> {
+    async move {
+        if !local::column_exists(db, "serve_job", "format").await? {
+            execute(
+                db,
+                "ALTER TABLE serve_job ADD COLUMN format TEXT NOT NULL DEFAULT 'dash';",
+            )
+            .await?;
+        }
+        if !local::column_exists(db, "serve_job", "audio_quality").await? {
+            execute(
+                db,
+                "ALTER TABLE serve_job ADD COLUMN audio_quality INTEGER;",
+            )
+            .await?;
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|x| x.version).unwrap_or_default()
+}
+
+async fn execute<C: ConnectionTrait>(db: &C, sql: &str) -> crate::Result<()> {
+    db.execute(Statement::from_string(
+        db.get_database_backend(),
+        sql.to_owned(),
+    ))
+    .await?;
+    Ok(())
+}
+
+/// 已执行的迁移 : (版本, 执行时间)
+async fn applied(db: &DatabaseConnection) -> crate::Result<Vec<(i64, i64)>> {
+    execute(
+        db,
+        "CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER PRIMARY KEY NOT NULL, name TEXT NOT NULL, applied_at INTEGER NOT NULL);",
+    )
+    .await?;
+    let rows = db
+        .query_all(Statement::from_string(
+            db.get_database_backend(),
+            "SELECT version, applied_at FROM schema_migrations ORDER BY version;".to_owned(),
+        ))
+        .await?;
+    let mut result = vec![];
+    for row in rows {
+        result.push((row.try_get("", "version")?, row.try_get("", "applied_at")?));
+    }
+    Ok(result)
+}
+
+/// 执行未执行的迁移, 返回执行的数量
+async fn migrate_db(db: &DatabaseConnection) -> crate::Result<usize> {
+    let applied = applied(db).await?;
+    let current = applied.last().map(|x| x.0).unwrap_or_default();
+    if current > latest_version() {
+        return Err(anyhow::Error::msg(format!(
+            "数据库版本 ({}) 高于程序支持的版本 ({}), 请更新bili",
+            current,
+            latest_version()
+        )));
+    }
+    let mut count = 0;
+    for migration in MIGRATIONS.iter().filter(|x| x.version > current) {
+        apply(db, migration).await.map_err(|err| {
+            anyhow::Error::msg(format!(
+                "数据库迁移失败 ({} {}) : {}",
+                migration.version, migration.name, err
+            ))
+        })?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// 在事务中执行一个迁移并记录版本, 出错时事务被丢弃并回滚
+async fn apply(db: &DatabaseConnection, migration: &Migration) -> crate::Result<()> {
+    let txn = db.begin().await?;
+    (migration.up)(&txn).await?;
+    txn.execute(Statement::from_sql_and_values(
+        txn.get_database_backend(),
+        "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, ?, ?);",
+        vec![
+            migration.version.into(),
+            migration.name.into(),
+            local::now_timestamp().into(),
+        ],
+    ))
+    .await?;
+    txn.commit().await?;
+    Ok(())
+}
+
+/// 启动时执行未执行的迁移
+pub(crate) async fn migrate_on_startup() -> crate::Result<()> {
+    let db = local::PROPERTY_DB.get().await.lock().await;
+    let count = migrate_db(db.deref()).await?;
+    // 新建的数据库会执行全部迁移, 不需要提示
+    if count > 0 && count < MIGRATIONS.len() {
+        cli::print_notice(format!(
+            "{}数据库已升级到版本 {}",
+            Emoji("🚚 ", ""),
+            latest_version()
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) async fn migrate() -> crate::Result<()> {
+    let db = local::PROPERTY_DB.get().await.lock().await;
+    let count = migrate_db(db.deref()).await?;
+    if count == 0 {
+        println!("数据库已是最新版本 ({})", latest_version());
+    } else {
+        println!(
+            "{}执行了{}个迁移, 数据库版本 : {}",
+            Emoji("✨", ""),
+            count,
+            latest_version()
+        );
+    }
+    Ok(())
+}
+
+/// 显示数据库版本和每个迁移的状态
+pub(crate) async fn status() -> crate::Result<()> {
+    let db = local::PROPERTY_DB.get().await.lock().await;
+    let applied = applied(db.deref()).await?;
+    println!("数据库 : {}", local::properties_db_path());
+    println!(
+        "版本 : {} (最新 {})",
+        applied.last().map(|x| x.0).unwrap_or_default(),
+        latest_version()
+    );
+    for migration in MIGRATIONS {
+        match applied.iter().find(|x| x.0 == migration.version) {
+            Some((_, applied_at)) => println!(
+                "  {} {:>3} {} ({})",
+                style("✓").green(),
+                migration.version,
+                migration.name,
+                user::format_timestamp(*applied_at)
+            ),
+            None => println!(
+                "  {} {:>3} {} ({})",
+                style("·").yellow(),
+                migration.version,
+                migration.name,
+                style("未执行").yellow()
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// 备份数据库, 没有指定路径时保存到配置目录下的 backups 目录
+pub(crate) async fn backup(path: &Option<String>) -> crate::Result<()> {
+    let path = match path {
+        Some(path) => path.clone(),
+        None => {
+            let dir = local::join_paths(vec![local::cfg_local_dir().as_str(), "backups"]);
+            std::fs::create_dir_all(&dir)?;
+            local::join_paths(vec![
+                dir.as_str(),
+                format!("properties-{}.db", local::now_timestamp()).as_str(),
+            ])
+        }
+    };
+    if std::path::Path::new(&path).exists() {
+        return Err(anyhow::Error::msg(format!("文件已存在 : {}", path)));
+    }
+    let db = local::PROPERTY_DB.get().await.lock().await;
+    db.execute(Statement::from_sql_and_values(
+        db.get_database_backend(),
+        "VACUUM INTO ?;",
+        vec![path.clone().into()],
+    ))
+    .await?;
+    local::restrict_permissions(&path, 0o600)?;
+    println!("{}已备份到 : {}", Emoji("✨", ""), path);
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use crate::{cli, local, secret};
+use console::{style, Emoji};
+
+/// 没有指定账号时使用的账号
+pub(crate) const DEFAULT_PROFILE: &str = "default";
+const CURRENT_PROFILE_PROPERTY: &str = "current_profile";
+pub(crate) const WEB_TOKEN_KEY: &str = "web_token";
+/// 账号的偏好设置, 覆盖配置文件中的同名配置项
+const PREF_PREFIX: &str = "pref.";
+
+/// 账号的配置项 : profile.<name>.<key>, 与全局的配置项分开
+pub(crate) fn profile_key(profile: &str, key: &str) -> String {
+    format!("profile.{}.{}", profile, key)
+}
+
+/// 当前使用的账号 : --profile 优先, 其次是 bili profile use 选择的账号
+pub(crate) async fn current() -> crate::Result<String> {
+    if let Some(profile) = cli::profile_value() {
+        return Ok(profile);
+    }
+    let profile = local::load_property(CURRENT_PROFILE_PROPERTY.to_owned()).await?;
+    if profile.is_empty() {
+        return Ok(DEFAULT_PROFILE.to_owned());
+    }
+    Ok(profile)
+}
+
+/// 读取当前账号的配置项, 登录信息会被解密, 旧版本保存的明文登录信息读取时加密保存
+pub(crate) async fn load_property(key: &str) -> crate::Result<String> {
+    let value = local::load_property(profile_key(&current().await?, key)).await?;
+    if key != WEB_TOKEN_KEY {
+        return Ok(value);
+    }
+    if !value.is_empty() && !secret::is_encrypted(&value) {
+        save_property(key, value.clone()).await?;
+    }
+    secret::decrypt(&value).await
+}
+
+/// 写入当前账号的配置项, 登录信息加密保存
+pub(crate) async fn save_property(key: &str, value: String) -> crate::Result<()> {
+    let value = if key == WEB_TOKEN_KEY {
+        secret::encrypt(&value).await?
+    } else {
+        value
+    };
+    local::save_property(profile_key(&current().await?, key), value).await
+}
+
+/// 账号的偏好设置 : (配置项, 值)
+pub(crate) async fn load_prefs(profile: &str) -> crate::Result<Vec<(String, String)>> {
+    let prefix = profile_key(profile, PREF_PREFIX);
+    let mut prefs = vec![];
+    for key in local::list_property_keys(&prefix).await? {
+        let value = local::load_property(key.clone()).await?;
+        if let Some(name) = key.strip_prefix(&prefix) {
+            prefs.push((name.to_owned(), value));
+        }
+    }
+    Ok(prefs)
+}
+
+/// 写入账号的偏好设置, 值为空时删除
+pub(crate) async fn save_pref(profile: &str, name: &str, value: &str) -> crate::Result<()> {
+    let key = profile_key(profile, &format!("{}{}", PREF_PREFIX, name));
+    if value.is_empty() {
+        local::remove_property(key).await
+    } else {
+        local::save_property(key, value.to_owned()).await
+    }
+}
+
+/// 全部账号的登录信息配置项
+pub(crate) async fn web_token_keys() -> crate::Result<Vec<String>> {
+    Ok(local::list_property_keys("profile.")
+        .await?
+        .into_iter()
+        .filter(|x| x.ends_with(&format!(".{}", WEB_TOKEN_KEY)))
+        .collect())
+}
+
+/// 已登录过的账号
+async fn profiles() -> crate::Result<Vec<String>> {
+    let mut profiles = vec![];
+    for key in web_token_keys().await? {
+        if let Some(name) = key
+            .strip_prefix("profile.")
+            .and_then(|x| x.strip_suffix(&format!(".{}", WEB_TOKEN_KEY)))
+        {
+            if !local::load_property(key.clone()).await?.is_empty() {
+                profiles.push(name.to_owned());
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+pub(crate) async fn list() -> crate::Result<()> {
+    let profiles = profiles().await?;
+    if profiles.is_empty() {
+        println!("没有账号, 请先登录 : bili login --profile <名称>");
+        return Ok(());
+    }
+    let current = current().await?;
+    for profile in profiles {
+        if profile == current {
+            println!("{} {}", Emoji("✨", "*"), style(profile).bold());
+        } else {
+            println!("  {}", profile);
+        }
+    }
+    Ok(())
+}
+
+/// 切换默认使用的账号
+pub(crate) async fn use_profile(name: &str) -> crate::Result<()> {
+    if !profiles().await?.iter().any(|x| x == name) {
+        return Err(anyhow::Error::msg(format!(
+            "账号不存在 : {}, 请先登录 : bili login --profile {}",
+            name, name
+        )));
+    }
+    local::save_property(CURRENT_PROFILE_PROPERTY.to_owned(), name.to_owned()).await?;
+    println!("{}使用账号 : {}", Emoji("✨", ""), name);
+    Ok(())
+}
+
+/// 删除账号的全部配置项, 包括登录信息和偏好设置
+pub(crate) async fn remove(name: &str) -> crate::Result<()> {
+    let keys = local::list_property_keys(&profile_key(name, "")).await?;
+    if keys.is_empty() {
+        return Err(anyhow::Error::msg(format!("账号不存在 : {}", name)));
+    }
+    for key in keys {
+        local::remove_property(key).await?;
+    }
+    let current = local::load_property(CURRENT_PROFILE_PROPERTY.to_owned()).await?;
+    if current == name {
+        local::remove_property(CURRENT_PROFILE_PROPERTY.to_owned()).await?;
+    }
+    println!("已删除账号 : {}", name);
+    Ok(())
+}
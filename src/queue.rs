@@ -0,0 +1,554 @@
+use crate::api::{self, DashVideo};
+use crate::download::{self, DownloadItem};
+use crate::ffmpeg::{self, MuxOptions};
+use crate::{cli, config, local, user};
+use anyhow::Context;
+use bilirust::{FNVAL_DASH, FNVAL_MP4, VIDEO_QUALITY_4K};
+use console::{style, Emoji};
+use futures::StreamExt;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// 失败后的最大重试次数
+const MAX_RETRIES: u32 = 3;
+
+/// 任务状态
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum JobState {
+    Waiting,
+    Video,
+    Audio,
+    Merging,
+    Done,
+    Exists,
+    Failed(String),
+    Cancelled,
+}
+
+impl JobState {
+    /// 接口中使用的状态名
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            JobState::Waiting => "waiting",
+            JobState::Video => "video",
+            JobState::Audio => "audio",
+            JobState::Merging => "merging",
+            JobState::Done => "done",
+            JobState::Exists => "exists",
+            JobState::Failed(_) => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    /// 由 name 和显示的信息还原状态, 用于读取保存的任务
+    pub(crate) fn from_name(name: &str, message: &str) -> Option<JobState> {
+        Some(match name {
+            "waiting" => JobState::Waiting,
+            "video" => JobState::Video,
+            "audio" => JobState::Audio,
+            "merging" => JobState::Merging,
+            "done" => JobState::Done,
+            "exists" => JobState::Exists,
+            "failed" => JobState::Failed(
+                message
+                    .strip_prefix("失败 : ")
+                    .unwrap_or(message)
+                    .to_owned(),
+            ),
+            "cancelled" => JobState::Cancelled,
+            _ => return None,
+        })
+    }
+
+    /// 是否已经结束, 不会再变化
+    pub(crate) fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobState::Done | JobState::Exists | JobState::Failed(_) | JobState::Cancelled
+        )
+    }
+}
+
+impl Display for JobState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobState::Waiting => write!(f, "等待中"),
+            JobState::Video => write!(f, "下载视频"),
+            JobState::Audio => write!(f, "下载音频"),
+            JobState::Merging => write!(f, "合并中"),
+            JobState::Done => write!(f, "完成"),
+            JobState::Exists => write!(f, "已存在"),
+            JobState::Failed(err) => write!(f, "失败 : {}", err),
+            JobState::Cancelled => write!(f, "已取消"),
+        }
+    }
+}
+
+/// 下载格式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum VideoFormat {
+    /// 分别下载视频和音频后合并, 清晰度最高
+    Dash,
+    /// 下载完整的mp4, 较长的视频分段下载后拼接
+    Mp4,
+}
+
+impl VideoFormat {
+    /// 接口和数据库中使用的名称
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            VideoFormat::Dash => "dash",
+            VideoFormat::Mp4 => "mp4",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dash" => Some(VideoFormat::Dash),
+            "mp4" => Some(VideoFormat::Mp4),
+            _ => None,
+        }
+    }
+}
+
+/// 下载队列中的一个任务, 进度通过 pb 展示或读取
+pub(crate) struct Job {
+    pub(crate) item: DownloadItem,
+    /// 视频清晰度, 为空时下载最高清晰度
+    pub(crate) quality: Option<i64>,
+    pub(crate) format: VideoFormat,
+    /// 音频质量, 为空时下载最高音质
+    pub(crate) audio_quality: Option<i64>,
+    pub(crate) pb: ProgressBar,
+    retries: AtomicU32,
+    state: Mutex<JobState>,
+    /// 取消后正在进行的合并也会停止
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Job {
+    pub(crate) fn new(item: DownloadItem, quality: Option<i64>, pb: ProgressBar) -> Self {
+        pb.set_style(waiting_style());
+        pb.set_prefix(item.title.clone());
+        pb.set_message(JobState::Waiting.to_string());
+        Job {
+            item,
+            quality: quality.or_else(config::quality),
+            format: VideoFormat::Dash,
+            audio_quality: None,
+            pb,
+            retries: AtomicU32::new(0),
+            state: Mutex::new(JobState::Waiting),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// 指定下载格式和音质
+    pub(crate) fn with_format(mut self, format: VideoFormat, audio_quality: Option<i64>) -> Self {
+        self.format = format;
+        self.audio_quality = audio_quality;
+        self
+    }
+
+    pub(crate) fn state(&self) -> JobState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub(crate) fn retries(&self) -> u32 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// 已经结束的任务, 例如读取保存的任务时
+    pub(crate) fn finished(self, state: JobState) -> Self {
+        self.pb.set_style(waiting_style());
+        self.set_state(state);
+        self.pb.abandon();
+        self
+    }
+
+    /// 任务已被中止, 更新状态并停止正在进行的合并
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.pb.set_style(waiting_style());
+        self.set_state(JobState::Cancelled);
+        self.pb.abandon();
+    }
+
+    fn set_state(&self, state: JobState) {
+        self.pb.set_message(match self.retries() {
+            0 => state.to_string(),
+            retries => format!("{} (重试 {})", state, retries),
+        });
+        *self.state.lock().unwrap() = state;
+    }
+}
+
+fn waiting_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{prefix:30!} {msg}")
+        .unwrap()
+}
+
+fn download_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{prefix:30!} [{bar:30.cyan/blue}] {bytes}/{total_bytes} {bytes_per_sec} ETA {eta} {msg}")
+        .unwrap()
+        .progress_chars("#>-")
+}
+
+fn merge_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{prefix:30!} [{bar:30.green}] {percent}% {msg}")
+        .unwrap()
+        .progress_chars("#>-")
+}
+
+/// 并发下载队列中的任务, 单个任务失败不影响其他任务
+pub(crate) async fn run_jobs(client: &bilirust::Client, jobs: &[Arc<Job>], concurrency: usize) {
+    futures::stream::iter(jobs)
+        .for_each_concurrent(concurrency.max(1), |job| async move {
+            let _ = run_job(client, job).await;
+        })
+        .await;
+}
+
+/// 在终端显示每个任务的实时进度并下载, 返回全部任务用于汇总
+pub(crate) async fn run_visible(
+    client: &bilirust::Client,
+    queued: Vec<(DownloadItem, Option<i64>)>,
+    concurrency: usize,
+) -> Vec<Arc<Job>> {
+    run_visible_as(client, queued, VideoFormat::Dash, None, concurrency).await
+}
+
+/// 和 run_visible 相同, 全部任务使用指定的格式和音质
+pub(crate) async fn run_visible_as(
+    client: &bilirust::Client,
+    queued: Vec<(DownloadItem, Option<i64>)>,
+    format: VideoFormat,
+    audio_quality: Option<i64>,
+    concurrency: usize,
+) -> Vec<Arc<Job>> {
+    let multi = MultiProgress::new();
+    let jobs: Vec<Arc<Job>> = queued
+        .into_iter()
+        .map(|(item, quality)| {
+            Arc::new(
+                Job::new(item, quality, multi.add(ProgressBar::new(0)))
+                    .with_format(format, audio_quality),
+            )
+        })
+        .collect();
+    run_jobs(client, &jobs, concurrency).await;
+    let _ = multi.clear();
+    jobs
+}
+
+/// 打印每个任务的结果
+pub(crate) fn print_summary(jobs: &[Arc<Job>]) {
+    println!();
+    println!("{}下载结束", Emoji("✨", ""));
+    for job in jobs {
+        let state = job.state();
+        let line = format!("  {} : {} (重试 {})", job.item.title, state, job.retries());
+        match state {
+            JobState::Failed(_) => println!("{}", style(line).red()),
+            _ => println!("{}", line),
+        }
+    }
+}
+
+/// 下载一个任务, 失败时从断点重试
+pub(crate) async fn run_job(client: &bilirust::Client, job: &Job) -> crate::Result<()> {
+    loop {
+        // 重试时已下载的部分清晰度一致, 可以续传; 指定 --resume 时第一次也续传
+        let resume = job.retries() > 0 || cli::resume_download_value();
+        match download_job(client, job, resume).await {
+            Ok(_) => return Ok(()),
+            Err(err) => {
+                if job.retries() >= MAX_RETRIES {
+                    job.pb.set_style(waiting_style());
+                    job.set_state(JobState::Failed(err.to_string()));
+                    job.pb.abandon();
+                    return Err(err);
+                }
+                job.retries.fetch_add(1, Ordering::Relaxed);
+                job.set_state(JobState::Waiting);
+                tokio::time::sleep(Duration::from_secs(3)).await;
+            }
+        }
+    }
+}
+
+async fn download_job(client: &bilirust::Client, job: &Job, resume: bool) -> crate::Result<()> {
+    let item = &job.item;
+    let mix_file = item.mix_file();
+    if mix_file.exists() {
+        save_archive(item).await?;
+        job.pb.set_style(waiting_style());
+        job.set_state(JobState::Exists);
+        job.pb.finish();
+        return Ok(());
+    }
+    if !item.folder.as_os_str().is_empty() {
+        tokio::fs::create_dir_all(&item.folder).await?;
+    }
+    let cid = match item.cid {
+        Some(cid) => cid,
+        None => client.bv_info(item.bvid.clone()).await?.cid,
+    };
+    if job.format == VideoFormat::Mp4 {
+        return download_mp4(client, job, cid, resume).await;
+    }
+    let media_url = client
+        .bv_download_url(item.bvid.clone(), cid, FNVAL_DASH, VIDEO_QUALITY_4K)
+        .await?;
+    // 只有指定了编码时才需要带编码信息的视频流
+    let codec = config::codec();
+    let videos: Vec<DashVideo> = match codec {
+        Some(_) => api::dash_videos(&item.bvid, cid).await?,
+        None => media_url.dash.video.iter().map(DashVideo::from).collect(),
+    };
+    let video = pick_video(&videos, job.quality, codec).with_context(|| "未找到视频")?;
+    if let Some(quality) = job.quality {
+        let ids: Vec<i64> = videos.iter().map(|x| x.id).collect();
+        if let Some(note) = user::explain_quality(quality, &ids).await {
+            job.pb.println(format!(
+                "{} : {}, 下载{}",
+                item.title,
+                note,
+                download::video_quality_name(video.id)
+            ));
+        }
+    }
+    let audio = job
+        .audio_quality
+        .and_then(|quality| media_url.dash.audio.iter().find(|x| x.id == quality))
+        .or_else(|| media_url.dash.audio.first())
+        .with_context(|| "未找到音频")?;
+
+    let video_file = item.folder.join(format!("{}.video", item.name));
+    let audio_file = item.folder.join(format!("{}.audio", item.name));
+
+    job.pb.set_style(download_style());
+    job.set_state(JobState::Video);
+    download::down_file_with(&video.base_url, &video_file, &job.pb, resume).await?;
+    job.set_state(JobState::Audio);
+    download::down_file_with(&audio.base_url, &audio_file, &job.pb, resume).await?;
+
+    job.pb.set_style(merge_style());
+    job.pb.set_length(0);
+    job.pb.set_position(0);
+    job.set_state(JobState::Merging);
+    let options = mux_options(job).await;
+    let cover_file = options.cover.clone();
+    let merge_video_file = video_file.clone();
+    let merge_audio_file = audio_file.clone();
+    tokio::task::spawn_blocking(move || {
+        mux_into(&mix_file, |output| {
+            ffmpeg::muxer().merge(
+                &[merge_video_file.as_path(), merge_audio_file.as_path()],
+                output,
+                &options,
+            )
+        })
+    })
+    .await??;
+    let mut temp_files = vec![video_file.as_path(), audio_file.as_path()];
+    temp_files.extend(cover_file.as_deref());
+    if let Some(kept) = download::remove_temp_files(&temp_files) {
+        job.pb.println(format!("{} : {}", item.title, kept));
+    }
+    finish_job(job).await
+}
+
+/// 下载mp4格式, 分段时逐段下载(可续传)后拼接, 只有一段时直接改名
+async fn download_mp4(
+    client: &bilirust::Client,
+    job: &Job,
+    cid: i64,
+    resume: bool,
+) -> crate::Result<()> {
+    let item = &job.item;
+    let mix_file = item.mix_file();
+    let media_url = client
+        .bv_download_url(
+            item.bvid.clone(),
+            cid,
+            FNVAL_MP4,
+            job.quality.unwrap_or(VIDEO_QUALITY_4K),
+        )
+        .await?;
+    if media_url.durl.is_empty() {
+        return Err(anyhow::Error::msg("未找到视频"));
+    }
+    if media_url.durl.len() > 1 {
+        ffmpeg::ffmpeg_info().require_demuxer("concat", "拼接分段视频")?;
+    }
+
+    job.pb.set_style(download_style());
+    job.set_state(JobState::Video);
+    let mut segment_files = vec![];
+    for (i, durl) in media_url.durl.iter().enumerate() {
+        let segment_file = item.folder.join(format!("{}.part{}", item.name, i + 1));
+        download::down_file_with(&durl.url, &segment_file, &job.pb, resume).await?;
+        segment_files.push(segment_file);
+    }
+    if segment_files.len() == 1 {
+        tokio::fs::rename(&segment_files[0], &mix_file).await?;
+        return finish_job(job).await;
+    }
+
+    job.pb.set_style(merge_style());
+    job.pb.set_length(0);
+    job.pb.set_position(0);
+    job.set_state(JobState::Merging);
+    let options = mux_options(job).await;
+    let cover_file = options.cover.clone();
+    let concat_files = segment_files.clone();
+    tokio::task::spawn_blocking(move || {
+        let segments: Vec<&Path> = concat_files.iter().map(|x| x.as_path()).collect();
+        mux_into(&mix_file, |output| {
+            ffmpeg::muxer().concat(&segments, output, &options)
+        })
+    })
+    .await??;
+    let mut segments: Vec<&Path> = segment_files.iter().map(|x| x.as_path()).collect();
+    segments.extend(cover_file.as_deref());
+    if let Some(kept) = download::remove_temp_files(&segments) {
+        job.pb.println(format!("{} : {}", item.title, kept));
+    }
+    finish_job(job).await
+}
+
+/// 封装选项 : 标题, 封面 (下载失败时不写入), 配置的音频语言
+async fn mux_options(job: &Job) -> MuxOptions {
+    let item = &job.item;
+    MuxOptions {
+        metadata: vec![("title".to_owned(), item.title.clone())],
+        cover: download::down_cover(item).await,
+        // 视频在前, 音频是第二个输出流
+        languages: config::audio_language()
+            .map(|language| vec![(1, language)])
+            .unwrap_or_default(),
+        progress: Some(job.pb.clone()),
+        cancelled: job.cancelled.clone(),
+    }
+}
+
+/// 先封装到临时文件, 成功后改名为 mix_file, 失败或取消时删除临时文件,
+/// 避免不完整的文件被当作已下载. 在阻塞线程中执行, 任务被中止时也会清理
+fn mux_into(mix_file: &Path, mux: impl FnOnce(&Path) -> crate::Result<()>) -> crate::Result<()> {
+    let temp_file = download::merging_file(mix_file);
+    match mux(&temp_file) {
+        Ok(()) => Ok(std::fs::rename(&temp_file, mix_file)?),
+        Err(err) => {
+            let _ = std::fs::remove_file(&temp_file);
+            Err(err)
+        }
+    }
+}
+
+/// 记录下载并标记任务完成
+async fn finish_job(job: &Job) -> crate::Result<()> {
+    save_archive(&job.item).await?;
+    job.pb.set_style(waiting_style());
+    job.set_state(JobState::Done);
+    job.pb.finish();
+    Ok(())
+}
+
+async fn save_archive(item: &DownloadItem) -> crate::Result<()> {
+    local::save_archive(
+        item.bvid.clone(),
+        item.title.clone(),
+        item.mix_file().to_string_lossy().into_owned(),
+    )
+    .await
+}
+
+/// 选择视频流 : 指定的清晰度, 没有时取不高于指定清晰度中最高的, 都没有时取最高清晰度,
+/// 同一清晰度中优先选择指定的编码
+pub(crate) fn pick_video(
+    videos: &[DashVideo],
+    quality: Option<i64>,
+    codec: Option<i64>,
+) -> Option<&DashVideo> {
+    let picked = match quality {
+        Some(quality) => videos
+            .iter()
+            .find(|x| x.id == quality)
+            .or_else(|| {
+                videos
+                    .iter()
+                    .filter(|x| x.id <= quality)
+                    .max_by_key(|x| x.id)
+            })
+            .or_else(|| videos.first()),
+        None => videos.first(),
+    }?;
+    codec
+        .and_then(|codec| {
+            videos
+                .iter()
+                .find(|x| x.id == picked.id && x.codecid == codec)
+        })
+        .or(Some(picked))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(id: i64, codecid: i64) -> DashVideo {
+        DashVideo {
+            id,
+            codecid,
+            base_url: format!("{}-{}", id, codecid),
+        }
+    }
+
+    #[test]
+    fn pick_video_quality_and_codec() {
+        let videos = vec![
+            video(80, 7),
+            video(80, 12),
+            video(80, 13),
+            video(64, 7),
+            video(64, 12),
+        ];
+        let pick = |quality, codec| pick_video(&videos, quality, codec).map(|x| (x.id, x.codecid));
+        assert_eq!(pick(None, None), Some((80, 7)));
+        assert_eq!(pick(None, Some(13)), Some((80, 13)));
+        assert_eq!(pick(Some(64), Some(12)), Some((64, 12)));
+        // 没有这个编码时使用同一清晰度的其他编码
+        assert_eq!(pick(Some(64), Some(13)), Some((64, 7)));
+        // 没有这个清晰度时取不高于它的最高清晰度
+        assert_eq!(pick(Some(74), Some(12)), Some((64, 12)));
+        assert_eq!(pick_video(&[], None, Some(7)).map(|x| x.id), None);
+    }
+
+    #[test]
+    fn mux_into_keeps_only_complete_files() {
+        let dir = std::env::temp_dir().join(format!("bili-mux-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mix_file = dir.join("video.mp4");
+
+        let failed = mux_into(&mix_file, |output| {
+            std::fs::write(output, "partial")?;
+            Err(anyhow::Error::msg("已取消"))
+        });
+        assert!(failed.is_err());
+        assert!(!mix_file.exists());
+        assert!(!download::merging_file(&mix_file).exists());
+
+        mux_into(&mix_file, |output| Ok(std::fs::write(output, "complete")?)).unwrap();
+        assert_eq!(std::fs::read_to_string(&mix_file).unwrap(), "complete");
+        assert!(!download::merging_file(&mix_file).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,516 @@
+use crate::download::{self, DownloadItem, ItemGroup};
+use crate::queue::{self, Job, JobState, VideoFormat};
+use crate::{api, cli, config, id, user};
+use console::{style, Emoji};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use lazy_static::lazy_static;
+use std::sync::Arc;
+
+lazy_static! {
+    static ref SHORT_PATTERN: regex::Regex =
+        regex::Regex::new(r"//b\d+\.tv/([0-9a-zA-Z]+)$").unwrap();
+    static ref WATCH_LATER_PATTERN: regex::Regex =
+        regex::Regex::new(r"bilibili\.com/(?:list/)?watchlater").unwrap();
+    static ref BV_PATTERN: regex::Regex = regex::Regex::new(r"BV[0-9a-zA-Z]{10}").unwrap();
+    static ref AV_PATTERN: regex::Regex =
+        regex::Regex::new(r"(?:\b(?i:av)|[?&]aid=)([0-9]+)").unwrap();
+    static ref SERIES_PATTERN: regex::Regex =
+        regex::Regex::new(r"(?:^|/)((?:ep|ss|md)[0-9]+)").unwrap();
+    static ref USER_COLLECTION_DETAIL_PATTERN: regex::Regex =
+        regex::Regex::new(r"/([0-9]+)/channel/collectiondetail\?sid=([0-9]+)").unwrap();
+    static ref USER_SERIES_DETAIL_PATTERN: regex::Regex =
+        regex::Regex::new(r"/([0-9]+)/channel/seriesdetail\?sid=([0-9]+)").unwrap();
+    static ref FAV_PATTERN: regex::Regex =
+        regex::Regex::new(r"(?:favlist\?(?:.*&)?fid=|medialist/(?:detail|play)/ml)([0-9]+)")
+            .unwrap();
+    static ref SPACE_PATTERN: regex::Regex =
+        regex::Regex::new(r"space\.bilibili\.com/([0-9]+)(?:/video|/upload/video)?/?(?:[?#].*)?$")
+            .unwrap();
+}
+
+/// 稍后再看列表的网址
+pub(crate) const WATCH_LATER_URL: &str = "https://www.bilibili.com/watchlater/#/list";
+
+/// 网址解析器 : 识别网址, 列出其中可以下载的视频, 下载时使用同一个列表
+pub(crate) trait Resolver: Sync {
+    /// 显示的名称
+    fn name(&self) -> &'static str;
+
+    fn matches(&self, url: &str) -> bool;
+
+    /// 列出可以下载的视频, 不下载
+    fn resolve<'a>(
+        &'a self,
+        client: &'a bilirust::Client,
+        url: &'a str,
+    ) -> BoxFuture<'a, crate::Result<Vec<ItemGroup>>>;
+
+    /// 全部任务结束后调用, 例如稍后再看下载完成后从列表中删除
+    fn after_download<'a>(&'a self, _jobs: &'a [Arc<Job>]) -> BoxFuture<'a, crate::Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+}
+
+/// 按顺序匹配, 匹配到多个时使用第一个
+static RESOLVERS: &[&dyn Resolver] = &[
+    &ShortResolver,
+    &WatchLaterResolver,
+    &BvResolver,
+    &SeriesResolver,
+    &CollectionResolver,
+    &SeriesDetailResolver,
+    &FavResolver,
+    &SpaceResolver,
+];
+
+/// 所有能处理这个网址的解析器
+pub(crate) fn matched_resolvers(url: &str) -> Vec<&'static dyn Resolver> {
+    RESOLVERS
+        .iter()
+        .copied()
+        .filter(|x| x.matches(url))
+        .collect()
+}
+
+/// 找到处理网址的解析器, 匹配到多个时提示并使用第一个
+fn find_resolver(url: &str) -> crate::Result<&'static dyn Resolver> {
+    let matched = matched_resolvers(url);
+    match matched.len() {
+        0 => Err(anyhow::Error::msg(format!("不支持的网址 : {}", url))),
+        1 => Ok(matched[0]),
+        _ => {
+            println!(
+                "{}",
+                style(format!(
+                    "网址同时匹配 {}, 使用 {}",
+                    matched
+                        .iter()
+                        .map(|x| x.name())
+                        .collect::<Vec<_>>()
+                        .join(" / "),
+                    matched[0].name()
+                ))
+                .yellow()
+            );
+            Ok(matched[0])
+        }
+    }
+}
+
+/// 通过下载队列下载 resolve 列出的视频, 有失败的视频时返回错误;
+/// interactive 为true时可以选择季和花絮, 只有一个视频时可以选择格式和清晰度
+pub(crate) async fn download(url: String, interactive: bool) -> crate::Result<()> {
+    let resolver = find_resolver(&url)?;
+    let client = user::login_client().await?;
+    let mut groups = resolver.resolve(&client, &url).await?;
+    if interactive {
+        groups = download::choose_groups(groups)?;
+    }
+    for group in &groups {
+        print_group(group);
+        download::print_skipped(group);
+    }
+    let items: Vec<DownloadItem> = groups.into_iter().flat_map(|x| x.items).collect();
+    println!();
+    if items.is_empty() {
+        println!("没有可以下载的视频");
+        return Ok(());
+    }
+    let (format, quality, audio_quality) =
+        if interactive && items.len() == 1 && !items[0].mix_file().exists() {
+            download::choose_options(&client, &items[0]).await?
+        } else {
+            (VideoFormat::Dash, None, None)
+        };
+    let jobs = queue::run_visible_as(
+        &client,
+        items.into_iter().map(|x| (x, quality)).collect(),
+        format,
+        audio_quality,
+        config::jobs(),
+    )
+    .await;
+    queue::print_summary(&jobs);
+    resolver.after_download(&jobs).await?;
+    let failed = jobs
+        .iter()
+        .filter(|x| matches!(x.state(), JobState::Failed(_)))
+        .count();
+    if failed > 0 {
+        return Err(anyhow::Error::msg(format!("{} 个视频下载失败", failed)));
+    }
+    Ok(())
+}
+
+/// 打印组的标题、视频数量和解析方式
+fn print_group(group: &ItemGroup) {
+    println!();
+    println!(
+        "{}{} ({} 个视频)",
+        Emoji("✨", ""),
+        style(&group.title).bold(),
+        group.items.len()
+    );
+    if let Some(strategy) = group.resolved_by {
+        println!("  解析方式 : {}", strategy);
+    }
+}
+
+/// 列出网址中可以下载的视频, 不下载
+pub(crate) async fn list_items(
+    client: &bilirust::Client,
+    url: String,
+) -> crate::Result<Vec<ItemGroup>> {
+    find_resolver(&url)?.resolve(client, &url).await
+}
+
+/// 显示网址会下载哪些视频
+pub(crate) async fn print_resolve(url: String) -> crate::Result<()> {
+    let client = user::login_client().await?;
+    let groups = list_items(&client, url).await?;
+    for group in groups {
+        print_group(&group);
+        for item in &group.items {
+            println!("  {} {}", item.bvid, item.title);
+            println!("    {}", item.mix_file().to_string_lossy());
+        }
+        download::print_skipped(&group);
+    }
+    Ok(())
+}
+
+/// 网址中的BV号, 只有av号时转换为BV号, av号无效时返回错误
+pub(crate) fn find_bv(url: &str) -> crate::Result<Option<String>> {
+    if let Some(find) = BV_PATTERN.find(url) {
+        return Ok(Some(find.as_str().to_owned()));
+    }
+    let aid = match AV_PATTERN.captures(url).and_then(|x| x.get(1)) {
+        Some(aid) => aid.as_str(),
+        None => return Ok(None),
+    };
+    let aid: i64 = aid
+        .parse()
+        .map_err(|_| anyhow::Error::msg(format!("不是有效的av号 : av{}", aid)))?;
+    Ok(Some(id::av_to_bv(aid)?))
+}
+
+/// 网址中的BV号, 没有时返回错误
+fn require_bv(url: &str) -> crate::Result<String> {
+    find_bv(url)?.ok_or_else(|| anyhow::Error::msg(format!("未找到BV号 : {}", url)))
+}
+
+/// 网址中的ep、ss或md, 例如 ep123
+fn find_series(url: &str) -> Option<String> {
+    Some(SERIES_PATTERN.captures(url)?.get(1)?.as_str().to_owned())
+}
+
+/// 网址中的ep或ss, md转为对应的ss
+async fn series_id(url: &str) -> crate::Result<String> {
+    let id = find_series(url).unwrap();
+    match id.strip_prefix("md") {
+        Some(md) => Ok(format!("ss{}", api::md_season_id(md.parse()?).await?)),
+        None => Ok(id),
+    }
+}
+
+/// 网址中的ep、ss或md, 转为番剧接口的参数
+pub(crate) async fn find_ep_or_ss(url: &str) -> crate::Result<Option<String>> {
+    let find = match find_series(url) {
+        Some(find) => find,
+        None => return Ok(None),
+    };
+    if let Some(id) = find.strip_prefix("ep") {
+        return Ok(Some(format!("ep_id={}", id)));
+    }
+    if let Some(id) = find.strip_prefix("md") {
+        return Ok(Some(format!(
+            "season_id={}",
+            api::md_season_id(id.parse()?).await?
+        )));
+    }
+    Ok(Some(format!("season_id={}", &find[2..])))
+}
+
+/// 网址中的两个数字, 例如 mid 和 sid
+fn find_ids(pattern: &regex::Regex, url: &str) -> crate::Result<(i64, i64)> {
+    let find = pattern
+        .captures(url)
+        .ok_or_else(|| anyhow::Error::msg(format!("不支持的网址 : {}", url)))?;
+    Ok((
+        find.get(1).unwrap().as_str().parse()?,
+        find.get(2).map_or(Ok(0), |x| x.as_str().parse())?,
+    ))
+}
+
+/// 解析短链接并重定向, 不是短链接时原样返回
+pub(crate) async fn resolve_short_url(url: String) -> crate::Result<String> {
+    let mut url = url;
+    if SHORT_PATTERN.is_match(url.as_str()) {
+        let rsp = reqwest::ClientBuilder::new()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?
+            .get(&url)
+            .send()
+            .await?;
+        match rsp.status().as_u16() {
+            302 => {
+                let headers = rsp.headers();
+                let location = headers.get("location");
+                if let Some(location) = location {
+                    url = location.to_str()?.to_owned();
+                }
+            }
+            _ => return Err(anyhow::Error::msg("resolve short links error")),
+        }
+    }
+    Ok(url)
+}
+
+/// 短链接, 重定向后再交给其他解析器
+struct ShortResolver;
+
+impl Resolver for ShortResolver {
+    fn name(&self) -> &'static str {
+        "短链接"
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        SHORT_PATTERN.is_match(url)
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        client: &'a bilirust::Client,
+        url: &'a str,
+    ) -> BoxFuture<'a, crate::Result<Vec<ItemGroup>>> {
+        async move { list_items(client, resolve_short_url(url.to_owned()).await?).await }.boxed()
+    }
+}
+
+/// 稍后再看
+struct WatchLaterResolver;
+
+impl Resolver for WatchLaterResolver {
+    fn name(&self) -> &'static str {
+        "稍后再看"
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        // 稍后再看中播放的单个视频网址带有BV号或av号, 由视频解析器处理
+        WATCH_LATER_PATTERN.is_match(url) && matches!(find_bv(url), Ok(None))
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        _client: &'a bilirust::Client,
+        _url: &'a str,
+    ) -> BoxFuture<'a, crate::Result<Vec<ItemGroup>>> {
+        async move { Ok(vec![download::watch_later_items().await?]) }.boxed()
+    }
+
+    fn after_download<'a>(&'a self, jobs: &'a [Arc<Job>]) -> BoxFuture<'a, crate::Result<()>> {
+        async move {
+            if cli::remove_after_value() {
+                download::remove_watched(jobs).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// 单个视频 (BV号或av号)
+struct BvResolver;
+
+impl Resolver for BvResolver {
+    fn name(&self) -> &'static str {
+        "视频"
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        // av号无效时也由这个解析器处理, 以便显示错误
+        !matches!(find_bv(url), Ok(None))
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        client: &'a bilirust::Client,
+        url: &'a str,
+    ) -> BoxFuture<'a, crate::Result<Vec<ItemGroup>>> {
+        async move { Ok(vec![download::bv_items(client, require_bv(url)?).await?]) }.boxed()
+    }
+}
+
+/// 番剧 (ep、ss或md)
+struct SeriesResolver;
+
+impl Resolver for SeriesResolver {
+    fn name(&self) -> &'static str {
+        "番剧"
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        SERIES_PATTERN.is_match(url)
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        client: &'a bilirust::Client,
+        url: &'a str,
+    ) -> BoxFuture<'a, crate::Result<Vec<ItemGroup>>> {
+        async move {
+            let id = series_id(url).await?;
+            // 介绍页不能用来解析剧集, 换成播放页
+            let url = if find_series(url).unwrap().starts_with("md") {
+                format!("https://www.bilibili.com/bangumi/play/{}", id)
+            } else {
+                url.to_owned()
+            };
+            download::series_items(client, id, url).await
+        }
+        .boxed()
+    }
+}
+
+/// 用户的合集 (collectiondetail)
+struct CollectionResolver;
+
+impl Resolver for CollectionResolver {
+    fn name(&self) -> &'static str {
+        "合集"
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        USER_COLLECTION_DETAIL_PATTERN.is_match(url)
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        client: &'a bilirust::Client,
+        url: &'a str,
+    ) -> BoxFuture<'a, crate::Result<Vec<ItemGroup>>> {
+        async move {
+            let (mid, sid) = find_ids(&USER_COLLECTION_DETAIL_PATTERN, url)?;
+            Ok(vec![download::collection_items(client, mid, sid).await?])
+        }
+        .boxed()
+    }
+}
+
+/// 用户的视频列表 (seriesdetail)
+struct SeriesDetailResolver;
+
+impl Resolver for SeriesDetailResolver {
+    fn name(&self) -> &'static str {
+        "视频列表"
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        USER_SERIES_DETAIL_PATTERN.is_match(url)
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        _client: &'a bilirust::Client,
+        url: &'a str,
+    ) -> BoxFuture<'a, crate::Result<Vec<ItemGroup>>> {
+        async move {
+            let (mid, sid) = find_ids(&USER_SERIES_DETAIL_PATTERN, url)?;
+            Ok(vec![download::series_detail_items(mid, sid).await?])
+        }
+        .boxed()
+    }
+}
+
+/// 收藏夹
+struct FavResolver;
+
+impl Resolver for FavResolver {
+    fn name(&self) -> &'static str {
+        "收藏夹"
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        FAV_PATTERN.is_match(url)
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        _client: &'a bilirust::Client,
+        url: &'a str,
+    ) -> BoxFuture<'a, crate::Result<Vec<ItemGroup>>> {
+        async move {
+            let (fid, _) = find_ids(&FAV_PATTERN, url)?;
+            Ok(vec![download::fav_items(fid).await?])
+        }
+        .boxed()
+    }
+}
+
+/// UP主的投稿
+struct SpaceResolver;
+
+impl Resolver for SpaceResolver {
+    fn name(&self) -> &'static str {
+        "UP主投稿"
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        SPACE_PATTERN.is_match(url)
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        _client: &'a bilirust::Client,
+        url: &'a str,
+    ) -> BoxFuture<'a, crate::Result<Vec<ItemGroup>>> {
+        async move {
+            let (mid, _) = find_ids(&SPACE_PATTERN, url)?;
+            Ok(vec![
+                download::space_items(mid, &cli::space_filter_value()).await?,
+            ])
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(url: &str) -> Vec<&'static str> {
+        matched_resolvers(url).iter().map(|x| x.name()).collect()
+    }
+
+    #[test]
+    fn watch_later_list() {
+        assert_eq!(
+            names("https://www.bilibili.com/watchlater/#/list"),
+            vec!["稍后再看"]
+        );
+        assert_eq!(
+            names("https://www.bilibili.com/list/watchlater"),
+            vec!["稍后再看"]
+        );
+    }
+
+    #[test]
+    fn watch_later_single_video() {
+        assert_eq!(
+            names("https://www.bilibili.com/list/watchlater?bvid=BV17x411w7KC&oid=170001"),
+            vec!["视频"]
+        );
+        assert_eq!(
+            names("https://www.bilibili.com/list/watchlater?aid=170001"),
+            vec!["视频"]
+        );
+    }
+
+    #[test]
+    fn invalid_av_is_reported() {
+        assert_eq!(names("av0"), vec!["视频"]);
+        assert!(find_bv("av0").is_err());
+        assert_eq!(find_bv("av170001").unwrap().unwrap(), "BV17x411w7KC");
+    }
+}
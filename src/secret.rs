@@ -0,0 +1,259 @@
+use std::path::Path;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use console::{style, Emoji};
+use dialoguer::Password;
+use tokio::sync::OnceCell;
+
+use crate::{local, profile};
+
+/// 加密后的值的前缀, 没有前缀的是旧版本保存的明文
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+/// 使用密码时保存盐的配置项, 存在时表示使用密码生成密钥
+const SALT_PROPERTY: &str = "secret_salt";
+/// 用来校验密码是否正确的配置项
+const CHECK_PROPERTY: &str = "secret_check";
+const CHECK_TEXT: &str = "bili";
+/// 不交互输入密码时使用的环境变量
+const PASSPHRASE_ENV: &str = "BILI_PASSPHRASE";
+const KEY_FILE: &str = "secret.key";
+const NONCE_LEN: usize = 12;
+
+static KEY: OnceCell<Key<Aes256Gcm>> = OnceCell::const_new();
+
+fn key_file() -> String {
+    local::join_paths(vec![local::cfg_local_dir().as_str(), KEY_FILE])
+}
+
+/// 更换密钥时先写入的新密钥文件, 数据库更新完成后才替换 key_file
+fn pending_key_file() -> String {
+    format!("{}.new", key_file())
+}
+
+/// 读取密钥 : 使用密码时由密码生成, 否则从密钥文件读取, 密钥文件不存在时生成
+async fn key() -> crate::Result<&'static Key<Aes256Gcm>> {
+    KEY.get_or_try_init(|| async {
+        let salt = local::load_property(SALT_PROPERTY.to_owned()).await?;
+        let check = local::load_property(CHECK_PROPERTY.to_owned()).await?;
+        if salt.is_empty() {
+            return load_or_create_key_file(&check);
+        }
+        let key = derive_key(&read_passphrase(false)?, &STANDARD.decode(salt)?)?;
+        if !check_matches(&key, &check) {
+            return Err(anyhow::Error::msg("密码错误"));
+        }
+        Ok(key)
+    })
+    .await
+}
+
+fn check_matches(key: &Key<Aes256Gcm>, check: &str) -> bool {
+    decrypt_with(key, check).ok().as_deref() == Some(CHECK_TEXT)
+}
+
+/// 读取密钥文件, 有校验值时确认密钥与数据库一致
+fn load_or_create_key_file(check: &str) -> crate::Result<Key<Aes256Gcm>> {
+    let path = key_file();
+    // 更换密钥中断时新密钥还没有替换, 数据库已经使用新密钥时完成替换, 否则丢弃
+    let pending = pending_key_file();
+    if Path::new(&pending).exists() {
+        match read_key_file(&pending) {
+            Result::Ok(key) if !check.is_empty() && check_matches(&key, check) => {
+                std::fs::rename(&pending, &path)?;
+                return Ok(key);
+            }
+            _ => std::fs::remove_file(&pending)?,
+        }
+    }
+    if Path::new(&path).exists() {
+        let key = read_key_file(&path)?;
+        if !check.is_empty() && !check_matches(&key, check) {
+            return Err(anyhow::Error::msg(format!(
+                "密钥文件与保存的登录信息不匹配 : {}",
+                path
+            )));
+        }
+        return Ok(key);
+    }
+    let key = Aes256Gcm::generate_key(OsRng);
+    write_key_file(&path, &key)?;
+    Ok(key)
+}
+
+fn read_key_file(path: &str) -> crate::Result<Key<Aes256Gcm>> {
+    let key = STANDARD.decode(std::fs::read_to_string(path)?.trim())?;
+    if key.len() != 32 {
+        return Err(anyhow::Error::msg(format!("密钥文件已损坏 : {}", path)));
+    }
+    Ok(*Key::<Aes256Gcm>::from_slice(&key))
+}
+
+fn write_key_file(path: &str, key: &Key<Aes256Gcm>) -> crate::Result<()> {
+    std::fs::write(path, STANDARD.encode(key))?;
+    local::restrict_permissions(path, 0o600)?;
+    Ok(())
+}
+
+fn read_passphrase(confirm: bool) -> crate::Result<String> {
+    if let Result::Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let mut input = Password::new();
+    input.with_prompt("请输入保存登录信息的密码");
+    if confirm {
+        input.with_confirmation("请再次输入密码", "两次输入的密码不一致");
+    }
+    Ok(input.interact()?)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> crate::Result<Key<Aes256Gcm>> {
+    let mut key = Key::<Aes256Gcm>::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow::Error::msg(format!("生成密钥失败 : {}", err)))?;
+    Ok(key)
+}
+
+fn encrypt_with(key: &Key<Aes256Gcm>, plain: &str) -> crate::Result<String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut data = nonce.to_vec();
+    data.extend(
+        Aes256Gcm::new(key)
+            .encrypt(&nonce, plain.as_bytes())
+            .map_err(|_| anyhow::Error::msg("加密失败"))?,
+    );
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(data)))
+}
+
+fn decrypt_with(key: &Key<Aes256Gcm>, value: &str) -> crate::Result<String> {
+    let data = match value.strip_prefix(ENCRYPTED_PREFIX) {
+        Some(data) => STANDARD.decode(data)?,
+        None => return Ok(value.to_owned()),
+    };
+    if data.len() < NONCE_LEN {
+        return Err(anyhow::Error::msg("加密数据已损坏"));
+    }
+    let (nonce, data) = data.split_at(NONCE_LEN);
+    let plain = Aes256Gcm::new(key)
+        .decrypt(Nonce::from_slice(nonce), data)
+        .map_err(|_| anyhow::Error::msg("解密失败, 密钥或密码不正确"))?;
+    Ok(String::from_utf8(plain)?)
+}
+
+/// 是否是加密后的值
+pub(crate) fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// 加密, 空值不加密
+pub(crate) async fn encrypt(plain: &str) -> crate::Result<String> {
+    if plain.is_empty() {
+        return Ok(String::default());
+    }
+    encrypt_with(key().await?, plain)
+}
+
+/// 解密, 明文原样返回
+pub(crate) async fn decrypt(value: &str) -> crate::Result<String> {
+    if !is_encrypted(value) {
+        return Ok(value.to_owned());
+    }
+    decrypt_with(key().await?, value)
+}
+
+/// 更换密钥并重新加密全部登录信息
+pub(crate) async fn rekey(use_passphrase: bool) -> crate::Result<()> {
+    // 先确认当前的密钥或密码可用
+    key().await?;
+    let mut values = vec![];
+    for k in profile::web_token_keys().await? {
+        let value = local::load_property(k.clone()).await?;
+        values.push((k, decrypt(&value).await?));
+    }
+
+    let (key, salt) = if use_passphrase {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let key = derive_key(&read_passphrase(true)?, &salt)?;
+        (key, Some(STANDARD.encode(salt)))
+    } else {
+        let key = Aes256Gcm::generate_key(OsRng);
+        write_key_file(&pending_key_file(), &key)?;
+        (key, None)
+    };
+
+    // 登录信息和盐/校验值在同一个事务中写入, 中断时数据库仍然全部使用旧密钥
+    let mut changes = vec![
+        (SALT_PROPERTY.to_owned(), salt),
+        (
+            CHECK_PROPERTY.to_owned(),
+            Some(encrypt_with(&key, CHECK_TEXT)?),
+        ),
+    ];
+    for (k, value) in &values {
+        if value.is_empty() {
+            continue;
+        }
+        changes.push((k.clone(), Some(encrypt_with(&key, value)?)));
+    }
+    local::update_properties(changes).await?;
+    // 数据库已经使用新密钥, 最后再替换或删除密钥文件
+    if use_passphrase {
+        let _ = std::fs::remove_file(key_file());
+    } else {
+        std::fs::rename(pending_key_file(), key_file())?;
+    }
+    println!(
+        "{}已更换密钥, 重新加密了{}个账号的登录信息 ({})",
+        Emoji("🔑 ", ""),
+        values.iter().filter(|(_, v)| !v.is_empty()).count(),
+        if use_passphrase {
+            style(format!("使用密码, 可以通过环境变量{}提供", PASSPHRASE_ENV)).green()
+        } else {
+            style(format!("密钥文件 : {}", key_file())).green()
+        }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_round_trip() {
+        let key = Aes256Gcm::generate_key(OsRng);
+        let value = encrypt_with(&key, "SESSDATA=abc").unwrap();
+        assert!(is_encrypted(&value));
+        assert_ne!(value, encrypt_with(&key, "SESSDATA=abc").unwrap());
+        assert_eq!(decrypt_with(&key, &value).unwrap(), "SESSDATA=abc");
+    }
+
+    #[test]
+    fn decrypt_with_wrong_key() {
+        let key = Aes256Gcm::generate_key(OsRng);
+        let value = encrypt_with(&key, "SESSDATA=abc").unwrap();
+        let other = Aes256Gcm::generate_key(OsRng);
+        assert!(decrypt_with(&other, &value).is_err());
+        assert!(!check_matches(
+            &other,
+            &encrypt_with(&key, CHECK_TEXT).unwrap()
+        ));
+    }
+
+    #[test]
+    fn plaintext_pass_through() {
+        let key = Aes256Gcm::generate_key(OsRng);
+        assert!(!is_encrypted("{\"sessdata\":\"abc\"}"));
+        assert_eq!(
+            decrypt_with(&key, "{\"sessdata\":\"abc\"}").unwrap(),
+            "{\"sessdata\":\"abc\"}"
+        );
+        assert_eq!(decrypt_with(&key, "").unwrap(), "");
+    }
+}
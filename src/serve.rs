@@ -0,0 +1,345 @@
+use crate::download::DownloadItem;
+use crate::entities::serve_job;
+use crate::queue::{self, Job, JobState, VideoFormat};
+use crate::{api, config, local, resolver, user};
+use axum::extract::{Path, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use console::{style, Emoji};
+use futures::Stream;
+use indicatif::ProgressBar;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+
+type ApiResult = Result<Json<Value>, (StatusCode, String)>;
+
+/// 结束的任务保留的时间 (秒)
+const FINISHED_JOB_KEEP_SECS: i64 = 24 * 3600;
+/// 清理结束的任务的间隔
+const EVICT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const FINISHED_STATES: [&str; 4] = ["done", "exists", "failed", "cancelled"];
+
+/// 守护进程的共享状态
+struct ServeState {
+    client: bilirust::Client,
+    jobs: Mutex<Vec<ServeJob>>,
+    /// 限制同时下载的任务数量
+    semaphore: Semaphore,
+    /// 设置后每个请求都要带上这个令牌
+    token: Option<String>,
+}
+
+struct ServeJob {
+    id: u64,
+    url: String,
+    job: Arc<Job>,
+    /// 读取保存的任务时, 已经结束的任务没有
+    handle: Option<AbortHandle>,
+}
+
+/// 启动HTTP/JSON接口, 使用和命令行相同的下载队列
+pub(crate) async fn run(listen: &str, concurrency: usize) -> crate::Result<()> {
+    let addr: SocketAddr = listen.parse()?;
+    let token = config::serve_token();
+    if token.is_none() && !addr.ip().is_loopback() {
+        return Err(anyhow::Error::msg(format!(
+            "监听 {} 时其他机器也能访问, 需要先设置访问令牌 (配置项 serve_token 或环境变量 BILI_SERVE_TOKEN)",
+            addr
+        )));
+    }
+    let state = Arc::new(ServeState {
+        client: user::login_client().await?,
+        jobs: Mutex::new(vec![]),
+        semaphore: Semaphore::new(concurrency.max(1)),
+        token,
+    });
+    let restored = restore_jobs(&state).await?;
+    if restored > 0 {
+        println!(
+            "{}继续下载上次未完成的任务 : {} 个",
+            Emoji("🚚 ", ""),
+            restored
+        );
+    }
+    tokio::spawn(evict_jobs(state.clone()));
+    let app = Router::new()
+        .route("/jobs", get(list_jobs).post(submit_job))
+        .route("/jobs/:id", get(get_job).delete(cancel_job))
+        .route("/events", get(events))
+        .route("/login", get(login_status))
+        .route_layer(middleware::from_fn_with_state(state.clone(), check_token))
+        .with_state(state);
+    println!("{}监听 : http://{}", Emoji("✨", ""), addr);
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// 设置了令牌时检查 `Authorization: Bearer <令牌>`, 浏览器的 EventSource 不能设置请求头, 也可以用 `?token=<令牌>`
+async fn check_token<B>(
+    State(state): State<Arc<ServeState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = match &state.token {
+        Some(token) => token,
+        None => return next.run(request).await,
+    };
+    let header_token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+    let query_token = request
+        .uri()
+        .query()
+        .and_then(|query| query.split('&').find_map(|x| x.strip_prefix("token=")));
+    if header_token.or(query_token) == Some(token.as_str()) {
+        next.run(request).await
+    } else {
+        (StatusCode::UNAUTHORIZED, "需要访问令牌".to_owned()).into_response()
+    }
+}
+
+fn bad_request(err: impl ToString) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, err.to_string())
+}
+
+fn not_found(id: u64) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("任务不存在 : {}", id))
+}
+
+fn job_json(job: &ServeJob) -> Value {
+    let state = job.job.state();
+    json!({
+        "id": job.id,
+        "url": job.url,
+        "title": job.job.item.title,
+        "bvid": job.job.item.bvid,
+        "file": job.job.item.mix_file().to_string_lossy(),
+        "quality": job.job.quality,
+        "format": job.job.format.name(),
+        "audio_quality": job.job.audio_quality,
+        "state": state.name(),
+        "message": state.to_string(),
+        "retries": job.job.retries(),
+        "position": job.job.pb.position(),
+        "length": job.job.pb.length(),
+        "speed": job.job.pb.per_sec() as u64,
+        "eta": job.job.pb.eta().as_secs(),
+    })
+}
+
+/// 在队列中下载, 结束后保存状态
+fn start_job(state: &Arc<ServeState>, id: u64, url: String, job: Arc<Job>) -> ServeJob {
+    let task_state = state.clone();
+    let task_job = job.clone();
+    let handle = tokio::spawn(async move {
+        let _permit = task_state.semaphore.acquire().await;
+        let _ = queue::run_job(&task_state.client, &task_job).await;
+        save_state(id, &task_job.state()).await;
+    })
+    .abort_handle();
+    ServeJob {
+        id,
+        url,
+        job,
+        handle: Some(handle),
+    }
+}
+
+async fn save_state(id: u64, job_state: &JobState) {
+    if let Err(err) = local::update_serve_job(
+        id as i64,
+        job_state.name().to_owned(),
+        job_state.to_string(),
+    )
+    .await
+    {
+        println!("{}", style(format!("保存任务 {} 失败 : {}", id, err)).red());
+    }
+}
+
+/// 读取保存的任务, 未结束的任务重新加入队列, 返回重新加入的数量
+async fn restore_jobs(state: &Arc<ServeState>) -> crate::Result<usize> {
+    let mut restored = 0;
+    for model in local::list_serve_jobs().await? {
+        let serve_job::Model {
+            id,
+            url,
+            bvid,
+            cid,
+            title,
+            folder,
+            name,
+            quality,
+            format,
+            audio_quality,
+            state: job_state,
+            message,
+            ..
+        } = model;
+        let item = DownloadItem {
+            bvid,
+            cid,
+            title,
+            folder: PathBuf::from(folder),
+            name,
+        };
+        let format = VideoFormat::from_name(&format).unwrap_or(VideoFormat::Dash);
+        let job = Job::new(item, quality, ProgressBar::hidden()).with_format(format, audio_quality);
+        let serve_job = match JobState::from_name(&job_state, &message) {
+            Some(job_state) if job_state.is_finished() => ServeJob {
+                id: id as u64,
+                url,
+                job: Arc::new(job.finished(job_state)),
+                handle: None,
+            },
+            _ => {
+                restored += 1;
+                start_job(state, id as u64, url, Arc::new(job))
+            }
+        };
+        state.jobs.lock().unwrap().push(serve_job);
+    }
+    Ok(restored)
+}
+
+/// 定时删除结束较久的任务
+async fn evict_jobs(state: Arc<ServeState>) {
+    loop {
+        tokio::time::sleep(EVICT_INTERVAL).await;
+        let before = local::now_timestamp() - FINISHED_JOB_KEEP_SECS;
+        match local::remove_serve_jobs_before(&FINISHED_STATES, before).await {
+            Ok(ids) => state
+                .jobs
+                .lock()
+                .unwrap()
+                .retain(|x| !ids.contains(&(x.id as i64))),
+            Err(err) => println!("{}", style(format!("清理任务失败 : {}", err)).red()),
+        }
+    }
+}
+
+fn jobs_json(state: &ServeState) -> Value {
+    Value::Array(state.jobs.lock().unwrap().iter().map(job_json).collect())
+}
+
+/// GET /jobs
+async fn list_jobs(State(state): State<Arc<ServeState>>) -> Json<Value> {
+    Json(jobs_json(&state))
+}
+
+/// GET /jobs/:id
+async fn get_job(State(state): State<Arc<ServeState>>, Path(id): Path<u64>) -> ApiResult {
+    let jobs = state.jobs.lock().unwrap();
+    match jobs.iter().find(|x| x.id == id) {
+        Some(job) => Ok(Json(job_json(job))),
+        None => Err(not_found(id)),
+    }
+}
+
+/// POST /jobs `{"url": "...", "quality": 80, "format": "dash", "audio_quality": 30280}`,
+/// 网址中的所有视频各成为一个任务, format 为 dash (默认) 或 mp4
+async fn submit_job(State(state): State<Arc<ServeState>>, Json(body): Json<Value>) -> ApiResult {
+    let url = body["url"]
+        .as_str()
+        .ok_or_else(|| bad_request("缺少 url"))?;
+    let url = crate::cli::check_download_url(url).map_err(bad_request)?;
+    let quality = body["quality"].as_i64();
+    let format = match body["format"].as_str() {
+        Some(name) => VideoFormat::from_name(name)
+            .ok_or_else(|| bad_request(format!("format 应为 dash 或 mp4 : {}", name)))?,
+        None => VideoFormat::Dash,
+    };
+    let audio_quality = body["audio_quality"].as_i64();
+    let groups = resolver::list_items(&state.client, url.clone())
+        .await
+        .map_err(bad_request)?;
+
+    let mut created = vec![];
+    for item in groups.into_iter().flat_map(|x| x.items) {
+        let model = local::add_serve_job(
+            url.clone(),
+            &item,
+            quality,
+            format.name().to_owned(),
+            audio_quality,
+        )
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        let job = Arc::new(
+            Job::new(item, quality, ProgressBar::hidden()).with_format(format, audio_quality),
+        );
+        let serve_job = start_job(&state, model.id as u64, url.clone(), job);
+        created.push(job_json(&serve_job));
+        state.jobs.lock().unwrap().push(serve_job);
+    }
+    Ok(Json(Value::Array(created)))
+}
+
+/// DELETE /jobs/:id
+async fn cancel_job(State(state): State<Arc<ServeState>>, Path(id): Path<u64>) -> ApiResult {
+    let (json, cancelled) = {
+        let jobs = state.jobs.lock().unwrap();
+        let job = jobs
+            .iter()
+            .find(|x| x.id == id)
+            .ok_or_else(|| not_found(id))?;
+        let cancelled = !job.job.state().is_finished();
+        if cancelled {
+            if let Some(handle) = &job.handle {
+                handle.abort();
+            }
+            job.job.cancel();
+        }
+        (job_json(job), cancelled)
+    };
+    if cancelled {
+        save_state(id, &JobState::Cancelled).await;
+    }
+    Ok(Json(json))
+}
+
+/// GET /events, 每秒推送一次全部任务 (SSE)
+async fn events(
+    State(state): State<Arc<ServeState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = futures::stream::unfold(state, |state| async move {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let event = Event::default()
+            .event("jobs")
+            .data(jobs_json(&state).to_string());
+        Some((Ok(event), state))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// GET /login, 守护进程一直运行, 每次都重新查询
+async fn login_status() -> ApiResult {
+    let web_token = user::load_web_token().await.map_err(bad_request)?;
+    if web_token.is_none() {
+        return Ok(Json(json!({ "login": false })));
+    }
+    match api::account().await {
+        Ok(account) if account.is_login => Ok(Json(json!({
+            "login": true,
+            "name": account.name,
+            "mid": account.mid,
+            "vip": account.vip,
+        }))),
+        Ok(_) => Ok(Json(json!({ "login": false }))),
+        Err(err) => Ok(Json(json!({ "login": false, "error": err.to_string() }))),
+    }
+}
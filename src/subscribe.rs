@@ -0,0 +1,101 @@
+use crate::queue::{self, JobState};
+use crate::{local, resolver, user};
+use console::{style, Emoji};
+use std::time::Duration;
+
+/// 添加订阅, 先解析一次网址确认可以下载
+pub(crate) async fn add(url: &str) -> crate::Result<()> {
+    let client = user::login_client().await?;
+    let groups = resolver::list_items(&client, url.to_owned()).await?;
+    let title = groups
+        .iter()
+        .map(|x| x.title.as_str())
+        .collect::<Vec<_>>()
+        .join(" / ");
+    let subscription = local::add_subscription(url.to_owned(), title).await?;
+    println!(
+        "{}已订阅 : [{}] {}",
+        Emoji("✨", ""),
+        subscription.id,
+        subscription.title
+    );
+    Ok(())
+}
+
+pub(crate) async fn list() -> crate::Result<()> {
+    let subscriptions = local::list_subscriptions().await?;
+    if subscriptions.is_empty() {
+        println!("没有订阅");
+    }
+    for subscription in subscriptions {
+        println!("[{}] {}", subscription.id, subscription.title);
+        println!("    {}", subscription.url);
+    }
+    Ok(())
+}
+
+pub(crate) async fn remove(id: i64) -> crate::Result<()> {
+    if local::remove_subscription(id).await? {
+        println!("已删除订阅 : {}", id);
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!("订阅不存在 : {}", id)))
+    }
+}
+
+/// 同步全部订阅, 指定 watch 时每隔 watch 分钟同步一次
+pub(crate) async fn sync(watch: Option<u64>, concurrency: usize) -> crate::Result<()> {
+    let client = user::login_client().await?;
+    loop {
+        match (sync_once(&client, concurrency).await, watch) {
+            (Err(err), None) => return Err(err),
+            // 一直运行时, 一次同步出错不停止, 等下次再同步
+            (Err(err), Some(_)) => println!("{}", style(format!("同步失败 : {}", err)).red()),
+            (Ok(()), _) => (),
+        }
+        match watch {
+            Some(minutes) => {
+                println!();
+                println!("{} 分钟后再次同步", minutes);
+                tokio::time::sleep(Duration::from_secs(minutes.max(1) * 60)).await;
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+/// 对比下载记录, 只下载新的视频
+async fn sync_once(client: &bilirust::Client, concurrency: usize) -> crate::Result<()> {
+    for subscription in local::list_subscriptions().await? {
+        println!();
+        println!("{}同步 : {}", Emoji("✨", ""), subscription.title);
+        let groups = match resolver::list_items(client, subscription.url.clone()).await {
+            Ok(groups) => groups,
+            Err(err) => {
+                println!("{}", style(err).red());
+                continue;
+            }
+        };
+        let mut queued = vec![];
+        for item in groups.into_iter().flat_map(|x| x.items) {
+            if local::archive_contains(&item.bvid).await? || item.mix_file().exists() {
+                continue;
+            }
+            queued.push((item, None));
+        }
+        println!("  新视频 : {} 个", queued.len());
+        if !queued.is_empty() {
+            let jobs = queue::run_visible(client, queued, concurrency).await;
+            queue::print_summary(&jobs);
+            // 有失败的视频时不更新同步时间, 下次同步会重新下载
+            if jobs
+                .iter()
+                .any(|x| matches!(x.state(), JobState::Failed(_)))
+            {
+                continue;
+            }
+        }
+        local::touch_subscription(subscription.id).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use crate::download::{self, DownloadItem, ItemGroup};
+use crate::queue::{self, JobState};
+use crate::{cli, resolver, user};
+use bilirust::{FNVAL_DASH, VIDEO_QUALITY_4K};
+use console::{style, Emoji, Term};
+use dialoguer::{Input, MultiSelect, Select};
+use itertools::Itertools;
+
+/// 统一选择清晰度时的选项
+const QUALITIES: [i64; 6] = [120, 116, 80, 64, 32, 16];
+
+/// 交互式选择视频并加入队列, 然后并发下载
+pub(crate) async fn run(concurrency: usize) -> crate::Result<()> {
+    let client = user::login_client().await?;
+    let mut queued: Vec<(DownloadItem, Option<i64>)> = vec![];
+    loop {
+        println!();
+        let action = Select::new()
+            .with_prompt("请选择操作")
+            .default(0)
+            .items(&[
+                "添加网址".to_owned(),
+                format!("查看队列 ({})", queued.len()),
+                "开始下载".to_owned(),
+                "退出".to_owned(),
+            ])
+            .interact()?;
+        match action {
+            0 => match add_url(&client).await {
+                Ok(items) => {
+                    println!("{}加入队列 : {} 个视频", Emoji("✨", ""), items.len());
+                    queued.extend(items);
+                }
+                Err(err) => println!("{}", style(err).red()),
+            },
+            1 => edit_queue(&mut queued)?,
+            2 => {
+                if queued.is_empty() {
+                    println!("队列为空");
+                    continue;
+                }
+                let failed =
+                    download_queue(&client, std::mem::take(&mut queued), concurrency).await;
+                queued.extend(failed);
+            }
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+/// 输入网址, 浏览并勾选要下载的视频, 再选择清晰度
+async fn add_url(client: &bilirust::Client) -> crate::Result<Vec<(DownloadItem, Option<i64>)>> {
+    let url = Input::<String>::new()
+        .with_prompt("请输入视频网址")
+        .interact_text()?;
+    let url = cli::check_download_url(url.trim())?;
+    println!("解析中...");
+    let mut groups = resolver::list_items(client, url).await?;
+    groups.iter().for_each(download::print_skipped);
+
+    // 多季的番剧先选择季
+    if groups.len() > 1 {
+        let titles: Vec<String> = groups
+            .iter()
+            .map(|x| format!("{} ({} 个视频)", x.title, x.items.len()))
+            .collect();
+        let selects = MultiSelect::new()
+            .with_prompt("请选择要浏览的季")
+            .items(&titles)
+            .defaults(&vec![true; titles.len()])
+            .interact()?;
+        groups = groups
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| selects.contains(i))
+            .map(|(_, x)| x)
+            .collect();
+    }
+
+    let mut items = vec![];
+    for group in groups {
+        items.extend(choose_items(group)?);
+    }
+    if items.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mode = Select::new()
+        .with_prompt("选择清晰度")
+        .default(0)
+        .items(&["全部最高清晰度", "统一选择清晰度", "逐个选择清晰度"])
+        .interact()?;
+    Ok(match mode {
+        0 => items.into_iter().map(|x| (x, None)).collect(),
+        1 => {
+            let names: Vec<String> = QUALITIES
+                .iter()
+                .map(|x| download::video_quality_name(*x))
+                .collect();
+            let quality = QUALITIES[Select::new()
+                .with_prompt("选择视频质量 (没有时下载较低的清晰度)")
+                .default(0)
+                .items(&names)
+                .interact()?];
+            items.into_iter().map(|x| (x, Some(quality))).collect()
+        }
+        _ => {
+            let mut result = vec![];
+            for item in items {
+                let quality = choose_quality(client, &item).await?;
+                result.push((item, Some(quality)));
+            }
+            result
+        }
+    })
+}
+
+fn choose_items(group: ItemGroup) -> crate::Result<Vec<DownloadItem>> {
+    if group.items.len() == 1 {
+        return Ok(group.items);
+    }
+    let titles: Vec<&str> = group.items.iter().map(|x| x.title.as_str()).collect();
+    let selects = MultiSelect::new()
+        .with_prompt(format!("{} : 请选择要下载的视频", group.title))
+        .items(&titles)
+        .defaults(&vec![true; titles.len()])
+        .interact()?;
+    Ok(group
+        .items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| selects.contains(i))
+        .map(|(_, x)| x)
+        .collect())
+}
+
+async fn choose_quality(client: &bilirust::Client, item: &DownloadItem) -> crate::Result<i64> {
+    let cid = match item.cid {
+        Some(cid) => cid,
+        None => client.bv_info(item.bvid.clone()).await?.cid,
+    };
+    let media_url = client
+        .bv_download_url(item.bvid.clone(), cid, FNVAL_DASH, VIDEO_QUALITY_4K)
+        .await?;
+    let ids: Vec<i64> = media_url.dash.video.iter().map(|x| x.id).unique().collect();
+    if ids.is_empty() {
+        return Err(anyhow::Error::msg(format!("未找到视频 : {}", item.title)));
+    }
+    if let Some(note) = user::unavailable_qualities(&ids).await {
+        println!("{}", style(note).yellow());
+    }
+    let names: Vec<String> = ids
+        .iter()
+        .map(|x| download::video_quality_name(*x))
+        .collect();
+    Ok(ids[Select::new()
+        .with_prompt(format!("{} : 选择视频质量", item.title))
+        .default(0)
+        .items(&names)
+        .interact()?])
+}
+
+/// 查看队列, 取消勾选的从队列中移除
+fn edit_queue(queued: &mut Vec<(DownloadItem, Option<i64>)>) -> crate::Result<()> {
+    if queued.is_empty() {
+        println!("队列为空");
+        return Ok(());
+    }
+    let titles: Vec<String> = queued
+        .iter()
+        .map(|(item, quality)| {
+            format!(
+                "{} [{}]",
+                item.title,
+                quality
+                    .map(download::video_quality_name)
+                    .unwrap_or_else(|| "最高".to_owned())
+            )
+        })
+        .collect();
+    let selects = MultiSelect::new()
+        .with_prompt("下载队列 (取消勾选移除)")
+        .items(&titles)
+        .defaults(&vec![true; titles.len()])
+        .interact()?;
+    let mut i = 0;
+    queued.retain(|_| {
+        i += 1;
+        selects.contains(&(i - 1))
+    });
+    Ok(())
+}
+
+/// 下载队列并显示实时进度, 返回失败的任务以便重新加入队列
+async fn download_queue(
+    client: &bilirust::Client,
+    queued: Vec<(DownloadItem, Option<i64>)>,
+    concurrency: usize,
+) -> Vec<(DownloadItem, Option<i64>)> {
+    let jobs = queue::run_visible(client, queued, concurrency).await;
+    let _ = Term::stdout().flush();
+    queue::print_summary(&jobs);
+    let failed: Vec<(DownloadItem, Option<i64>)> = jobs
+        .iter()
+        .filter(|x| matches!(x.state(), JobState::Failed(_)))
+        .map(|x| (x.item.clone(), x.quality))
+        .collect();
+    if !failed.is_empty() {
+        println!("失败的 {} 个视频已放回队列", failed.len());
+    }
+    failed
+}
@@ -1,72 +1,532 @@
-use crate::local;
+use crate::{api, cli, download, local, profile};
 use bilirust::{from_str, WebToken};
+use chrono::{Local, TimeZone};
 use console::{style, Emoji};
+use dialoguer::Password;
 use image::Luma;
+use once_cell::sync::OnceCell;
 use qrcode::QrCode;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-use std::{path::Path, process::exit};
 use tokio::time;
 
+/// 上次确认登录有效的时间
+pub(crate) const WEB_TOKEN_CHECKED_PROPERTY: &str = "web_token_checked";
+
+/// 距离过期不到7天时提示重新登录
+const TOKEN_EXPIRE_WARNING_SECS: i64 = 7 * 24 * 3600;
+
+/// 每次运行只检查一次登录状态
+static VALIDATED: OnceCell<()> = OnceCell::new();
+
+/// 二维码过期后最多重新生成的次数
+const QR_MAX_REFRESH: usize = 3;
+
 pub(crate) async fn login(is_console: &bool) -> crate::Result<()> {
     let client = bilirust::Client::new();
-    let qr_data = client.login_qr().await.unwrap();
+    for refresh in 0..=QR_MAX_REFRESH {
+        if refresh > 0 {
+            println!(
+                "{}",
+                style(format!(
+                    "二维码已过期, 重新生成 ({}/{})",
+                    refresh, QR_MAX_REFRESH
+                ))
+                .yellow()
+            );
+        }
+        let qr_data = client
+            .login_qr()
+            .await
+            .map_err(|err| anyhow::Error::msg(format!("获取登录二维码失败 : {}", err)))?;
+        let qr_file = show_qr(&qr_data.url, *is_console)?;
+        let result = wait_qr(&client, &qr_data.oauth_key).await;
+        if let Some(qr_file) = qr_file {
+            let _ = std::fs::remove_file(qr_file);
+        }
+        if let Some(web_token) = result? {
+            let web_token_string = serde_json::to_string(&web_token)?;
+            profile::save_property(profile::WEB_TOKEN_KEY, web_token_string).await?;
+            println!(
+                "{}登陆成功！ 账号 : {}",
+                Emoji("✨", ":-)"),
+                profile::current().await?
+            );
+            return Ok(());
+        }
+    }
+    Err(anyhow::Error::msg(format!(
+        "二维码已过期{}次, 请重新执行登录",
+        QR_MAX_REFRESH + 1
+    )))
+}
 
-    if *is_console {
-        qr2term::print_qr(qr_data.url.clone().as_str()).unwrap();
-    } else {
-        let code = QrCode::new(qr_data.url.clone().as_str().as_bytes()).unwrap();
-        let image = code.render::<Luma<u8>>().build();
-        let path = Path::new("qr.png");
-        image.save(path).unwrap();
-        opener::open(path.as_os_str()).unwrap();
+/// 显示二维码, 图片保存在临时目录, 返回需要清理的图片路径. 无法打开图片时在控制台显示
+fn show_qr(url: &str, is_console: bool) -> crate::Result<Option<PathBuf>> {
+    if !is_console {
+        let code = QrCode::new(url.as_bytes())?;
+        let path = std::env::temp_dir().join(format!("bili-qr-{}.png", std::process::id()));
+        code.render::<Luma<u8>>().build().save(&path)?;
+        match opener::open(path.as_os_str()) {
+            Ok(_) => return Ok(Some(path)),
+            Err(err) => {
+                let _ = std::fs::remove_file(&path);
+                println!(
+                    "{}",
+                    style(format!("无法打开图片 ({}), 在控制台显示二维码", err)).yellow()
+                );
+            }
+        }
     }
-    println!("{}等待扫码中...", Emoji("🚚 ", ""));
+    qr2term::print_qr(url).map_err(|err| anyhow::Error::msg(err.to_string()))?;
+    Ok(None)
+}
 
+/// 轮询扫码状态, 登录成功返回token, 二维码过期返回None
+async fn wait_qr(client: &bilirust::Client, oauth_key: &str) -> crate::Result<Option<WebToken>> {
+    println!("{}等待扫码中...", Emoji("🚚 ", ""));
+    let mut scanned = false;
     loop {
         time::sleep(Duration::from_secs(3)).await;
-        let info = client.login_qr_info(qr_data.oauth_key.clone()).await;
-        match info {
-            Ok(info) => {
-                // -1：密钥错误
-                // -2：密钥超时
-                // -4：未扫描
-                // -5：未确认
-                match info.error_data {
-                    0 => {
-                        let web_token = client.login_qr_info_parse_token(info.url).unwrap();
-                        let web_token_string = serde_json::to_string(&web_token).unwrap();
-                        local::save_property("web_token".to_string(), web_token_string).await?;
-                        println!("{}登陆成功！", Emoji("✨", ":-)"));
-                        break;
-                    }
-                    -4 => continue,
-                    -5 => continue,
-                    -2 => panic!("time out"),
-                    other => panic!("ERROR : {}", other),
+        let info = client
+            .login_qr_info(oauth_key.to_owned())
+            .await
+            .map_err(|err| anyhow::Error::msg(format!("查询扫码状态失败 : {}", err)))?;
+        // -1：密钥错误
+        // -2：密钥超时
+        // -4：未扫描
+        // -5：未确认
+        match info.error_data {
+            0 => {
+                let web_token = client
+                    .login_qr_info_parse_token(info.url)
+                    .map_err(|err| anyhow::Error::msg(format!("解析登录信息失败 : {}", err)))?;
+                return Ok(Some(web_token));
+            }
+            -4 => continue,
+            -5 => {
+                if !scanned {
+                    scanned = true;
+                    println!("{}已扫码, 请在手机上确认登录...", Emoji("📱 ", ""));
                 }
             }
-            Err(err) => {
-                panic!("{}", err);
+            -2 => return Ok(None),
+            -1 => return Err(anyhow::Error::msg("二维码密钥错误, 请重新执行登录")),
+            other => return Err(anyhow::Error::msg(format!("未知的扫码状态 : {}", other))),
+        }
+    }
+}
+
+/// 使用浏览器导出的cookie登录 : cookies.txt 文件 (Netscape格式) 或 "SESSDATA=...; bili_jct=..." 字符串,
+/// 为 - 时从标准输入读取, 为空时提示输入
+pub(crate) async fn login_with_cookies(cookies: Option<&str>) -> crate::Result<()> {
+    let content = match cookies {
+        Some("-") => read_stdin()?,
+        Some(cookies) if Path::new(cookies).is_file() => tokio::fs::read_to_string(cookies).await?,
+        Some(cookies) if cookies.contains('=') => {
+            println!(
+                "{}",
+                style("cookie会留在shell历史中, 建议使用 --cookies - 从标准输入读取").yellow()
+            );
+            cookies.to_owned()
+        }
+        Some(cookies) => return Err(anyhow::Error::msg(format!("文件不存在 : {}", cookies))),
+        None => Password::new()
+            .with_prompt("请输入cookie (SESSDATA=...; bili_jct=...)")
+            .interact()?,
+    };
+    save_cookie_token(parse_cookies(&content)).await
+}
+
+fn read_stdin() -> crate::Result<String> {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)?;
+    Ok(input)
+}
+
+/// 只使用SESSDATA登录, 没有bili_jct时不能使用需要csrf的功能 (例如 --remove-after)
+pub(crate) async fn login_with_sessdata(sessdata: Option<&str>) -> crate::Result<()> {
+    let sessdata = match sessdata {
+        Some("-") => read_stdin()?,
+        Some(sessdata) => {
+            println!(
+                "{}",
+                style("SESSDATA会留在shell历史中, 建议使用 --sessdata - 从标准输入读取").yellow()
+            );
+            sessdata.to_owned()
+        }
+        None => Password::new().with_prompt("请输入SESSDATA").interact()?,
+    };
+    let sessdata = sessdata.trim();
+    if sessdata.is_empty() {
+        return Err(anyhow::Error::msg("SESSDATA为空"));
+    }
+    let mut cookies = HashMap::new();
+    cookies.insert("SESSDATA".to_owned(), (sessdata.to_owned(), 0));
+    save_cookie_token(cookies).await
+}
+
+/// cookie名称对应的值和过期时间
+type Cookies = HashMap<String, (String, i64)>;
+
+fn parse_cookies(content: &str) -> Cookies {
+    let mut cookies = HashMap::new();
+    for line in content.lines() {
+        // #HttpOnly_ 开头的行是有效的cookie, 其他 # 开头的行是注释
+        let line = line.trim().trim_start_matches("#HttpOnly_");
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() == 7 {
+            if fields[0].contains("bilibili.com") {
+                cookies.insert(
+                    fields[5].to_owned(),
+                    (fields[6].to_owned(), fields[4].parse().unwrap_or(0)),
+                );
+            }
+            continue;
+        }
+        for pair in line.trim_start_matches("Cookie:").split(';') {
+            if let Some((k, v)) = pair.split_once('=') {
+                cookies.insert(k.trim().to_owned(), (v.trim().to_owned(), 0));
             }
         }
     }
+    cookies
+}
+
+/// 用cookie生成和扫码登录相同的WebToken, 确认有效后保存
+async fn save_cookie_token(cookies: Cookies) -> crate::Result<()> {
+    let value = |name: &str| cookies.get(name).map(|x| x.0.clone()).unwrap_or_default();
+    let (sessdata, expires) = cookies
+        .get("SESSDATA")
+        .cloned()
+        .ok_or_else(|| anyhow::Error::msg("cookie中没有SESSDATA"))?;
+    let web_token: WebToken = serde_json::from_value(serde_json::json!({
+        "DedeUserID": value("DedeUserID"),
+        "DedeUserID__ckMd5": value("DedeUserID__ckMd5"),
+        "Expires": expires,
+        "SESSDATA": sessdata,
+        "bili_jct": value("bili_jct"),
+        "gourl": "",
+    }))?;
+
+    let mut client = bilirust::Client::new();
+    client.login_set_sess_data(web_token.sessdata.clone());
+    client
+        .my_info()
+        .await
+        .map_err(|err| anyhow::Error::msg(format!("cookie无效或已过期 : {}", err)))?;
 
+    profile::save_property(profile::WEB_TOKEN_KEY, serde_json::to_string(&web_token)?).await?;
+    println!(
+        "{}登陆成功！ 账号 : {}",
+        Emoji("✨", ":-)"),
+        profile::current().await?
+    );
+    if web_token.bili_jct.is_empty() {
+        println!(
+            "{}",
+            style("cookie中没有bili_jct, 需要csrf的功能将无法使用").yellow()
+        );
+    }
     Ok(())
 }
 
-pub(crate) async fn login_client() -> crate::Result<bilirust::Client> {
-    let property = local::load_property("web_token".to_owned()).await?;
-    if &property == "" {
-        println!("{}", style("需要登录!").cyan().bold());
-        exit(1);
+/// 读取保存的登录信息, 未登录时为空
+pub(crate) async fn load_web_token() -> crate::Result<Option<WebToken>> {
+    let property = profile::load_property(profile::WEB_TOKEN_KEY).await?;
+    if property.is_empty() {
+        return Ok(None);
     }
-    let token: WebToken = from_str(property.as_str())?;
+    Ok(Some(from_str(property.as_str())?))
+}
+
+pub(crate) async fn login_client() -> crate::Result<bilirust::Client> {
+    let token = match load_web_token().await? {
+        Some(token) => token,
+        None => return Err(anyhow::Error::msg("需要登录! 请先执行 bili login")),
+    };
     let mut client = bilirust::Client::new();
-    client.login_set_sess_data(token.sessdata);
+    client.login_set_sess_data(token.sessdata.clone());
+    if VALIDATED.set(()).is_ok() {
+        validate_token(&client, &token).await?;
+    }
     Ok(client)
 }
 
-pub(crate) async fn user_info() -> crate::Result<()> {
-    println!("{:?}", login_client().await?.my_info().await?);
+/// 检查登录是否有效, 已失效时返回错误, 快过期或需要刷新时提示重新登录 (不支持自动刷新)
+async fn validate_token(client: &bilirust::Client, token: &WebToken) -> crate::Result<()> {
+    if let Err(err) = client.my_info().await {
+        match account().await.map(|x| x.is_login) {
+            Ok(false) => {
+                return Err(anyhow::Error::msg("登录已失效, 请重新登录 : bili login"));
+            }
+            _ => {
+                cli::print_notice(style(format!("无法确认登录状态 : {}", err)).yellow());
+                return Ok(());
+            }
+        }
+    }
+    profile::save_property(
+        WEB_TOKEN_CHECKED_PROPERTY,
+        local::now_timestamp().to_string(),
+    )
+    .await?;
+    if let Some(warning) = token_warning(token).await {
+        cli::print_notice(style(warning).yellow());
+    }
     Ok(())
 }
+
+/// 登录快过期, 或者服务器要求刷新cookie时的提示
+async fn token_warning(token: &WebToken) -> Option<String> {
+    // 扫码登录不提供refresh_token, 无法自动刷新, 只能提示重新登录
+    if api::cookie_needs_refresh(&token.bili_jct)
+        .await
+        .unwrap_or(false)
+    {
+        return Some("登录信息需要刷新, 请重新登录 : bili login".to_owned());
+    }
+    if token.expires > 0 && token.expires - local::now_timestamp() < TOKEN_EXPIRE_WARNING_SECS {
+        return Some(format!(
+            "登录将于 {} 过期, 请重新登录 : bili login",
+            format_timestamp(token.expires)
+        ));
+    }
+    None
+}
+
+pub(crate) fn format_timestamp(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// 显示登录状态和过期时间
+pub(crate) async fn login_status() -> crate::Result<()> {
+    let token = match load_web_token().await? {
+        Some(token) => token,
+        None => {
+            println!("未登录");
+            return Ok(());
+        }
+    };
+    let account = account().await?;
+    if !account.is_login {
+        println!(
+            "{}",
+            style("登录已失效, 请重新登录 : bili login").red().bold()
+        );
+        return Ok(());
+    }
+    println!(
+        "{}已登录 : {} ({})",
+        Emoji("✨", ""),
+        account.name,
+        account.mid
+    );
+    println!("  账号 : {}", profile::current().await?);
+    if token.expires > 0 {
+        println!("  过期时间 : {}", format_timestamp(token.expires));
+    }
+    let checked = profile::load_property(WEB_TOKEN_CHECKED_PROPERTY).await?;
+    if let Ok(checked) = checked.parse::<i64>() {
+        println!("  上次检查 : {}", format_timestamp(checked));
+    }
+    if let Some(warning) = token_warning(&token).await {
+        println!("{}", style(warning).yellow());
+    }
+    Ok(())
+}
+
+/// 退出登录并删除保存的登录信息
+pub(crate) async fn logout() -> crate::Result<()> {
+    let token = match load_web_token().await? {
+        Some(token) => token,
+        None => {
+            println!("未登录");
+            return Ok(());
+        }
+    };
+    if let Err(err) = api::logout(&token.bili_jct).await {
+        println!(
+            "{}",
+            style(format!("通知服务器退出失败 : {}", err)).yellow()
+        );
+    }
+    profile::save_property(profile::WEB_TOKEN_KEY, "".to_owned()).await?;
+    profile::save_property(WEB_TOKEN_CHECKED_PROPERTY, "".to_owned()).await?;
+    println!("{}已退出登录", Emoji("✨", ""));
+    Ok(())
+}
+
+/// 账号信息报告中列出的清晰度
+const REPORT_QUALITIES: [i64; 7] = [120, 116, 112, 80, 64, 32, 16];
+
+static ACCOUNT: tokio::sync::OnceCell<api::Account> = tokio::sync::OnceCell::const_new();
+
+/// 当前账号的信息, 每个进程只在第一次查询成功后缓存
+pub(crate) async fn account() -> crate::Result<&'static api::Account> {
+    ACCOUNT.get_or_try_init(api::account).await
+}
+
+/// 当前账号的mid, 未登录时返回错误
+pub(crate) async fn my_mid() -> crate::Result<i64> {
+    let account = account().await?;
+    if !account.is_login {
+        return Err(anyhow::Error::msg("需要登录!"));
+    }
+    Ok(account.mid)
+}
+
+/// 当前账号是否为大会员, 未登录或查询失败时为否
+pub(crate) async fn is_vip() -> bool {
+    account().await.map(|x| x.vip).unwrap_or(false)
+}
+
+/// 账号不能使用清晰度的原因, 可以使用时为None
+pub(crate) fn quality_restriction(account: &api::Account, quality: i64) -> Option<&'static str> {
+    if quality > 80 && !account.vip {
+        Some("需要大会员")
+    } else if quality > 32 && !account.is_login {
+        Some("需要登录")
+    } else {
+        None
+    }
+}
+
+/// 视频中没有指定清晰度时说明原因, 有这个清晰度时为None
+pub(crate) async fn explain_quality(quality: i64, available: &[i64]) -> Option<String> {
+    if available.contains(&quality) {
+        return None;
+    }
+    Some(format!(
+        "没有{}清晰度 : {}",
+        download::video_quality_name(quality),
+        quality_restriction(&account().await.cloned().unwrap_or_default(), quality)
+            .unwrap_or("视频不提供这个清晰度")
+    ))
+}
+
+/// 因为账号权限而没有出现在可选列表中的清晰度
+pub(crate) async fn unavailable_qualities(available: &[i64]) -> Option<String> {
+    let account = account().await.cloned().unwrap_or_default();
+    let restricted: Vec<String> = REPORT_QUALITIES
+        .iter()
+        .filter(|x| !available.contains(x))
+        .filter_map(|x| {
+            quality_restriction(&account, *x)
+                .map(|reason| format!("{} ({})", download::video_quality_name(*x), reason))
+        })
+        .collect();
+    if restricted.is_empty() {
+        return None;
+    }
+    Some(format!("当前账号不能下载 : {}", restricted.join(", ")))
+}
+
+fn vip_name(account: &api::Account) -> String {
+    if !account.vip {
+        return "不是大会员".to_owned();
+    }
+    if !account.vip_label.is_empty() {
+        return account.vip_label.clone();
+    }
+    match account.vip_type {
+        2 => "年度大会员".to_owned(),
+        _ => "大会员".to_owned(),
+    }
+}
+
+/// 显示账号信息和可以下载的清晰度
+pub(crate) async fn user_info(json: bool) -> crate::Result<()> {
+    login_client().await?;
+    let account = account().await?;
+    if !account.is_login {
+        return Err(anyhow::Error::msg("需要登录!"));
+    }
+    let vip_due = if account.vip && account.vip_due > 0 {
+        Some(account.vip_due / 1000)
+    } else {
+        None
+    };
+
+    if json {
+        let qualities: Vec<serde_json::Value> = REPORT_QUALITIES
+            .iter()
+            .map(|x| {
+                let restriction = quality_restriction(account, *x);
+                serde_json::json!({
+                    "id": x,
+                    "name": download::video_quality_name(*x),
+                    "available": restriction.is_none(),
+                    "reason": restriction,
+                })
+            })
+            .collect();
+        let report = serde_json::json!({
+            "profile": profile::current().await?,
+            "name": account.name,
+            "mid": account.mid,
+            "level": account.level,
+            "vip": account.vip,
+            "vip_type": account.vip_type,
+            "vip_name": vip_name(account),
+            "vip_due": vip_due,
+            "qualities": qualities,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("账号 : {}", profile::current().await?);
+    println!("名称 : {}", style(&account.name).bold());
+    println!("mid  : {}", account.mid);
+    println!("等级 : Lv{}", account.level);
+    match vip_due {
+        Some(due) => println!(
+            "会员 : {} (到期 {})",
+            style(vip_name(account)).magenta(),
+            format_timestamp(due)
+        ),
+        None => println!("会员 : {}", vip_name(account)),
+    }
+    println!("清晰度 :");
+    for quality in REPORT_QUALITIES {
+        let name = download::video_quality_name(quality);
+        match quality_restriction(account, quality) {
+            None => println!("  {} {}", style("✓").green(), name),
+            Some(reason) => println!("  {} {} ({})", style("✗").red(), name, reason),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_netscape_cookies() {
+        let content = "# Netscape HTTP Cookie File\n\
+            .bilibili.com\tTRUE\t/\tFALSE\t1700000000\tbili_jct\tcsrf\n\
+            #HttpOnly_.bilibili.com\tTRUE\t/\tTRUE\t1700000001\tSESSDATA\tsess%2C1\n\
+            .example.com\tTRUE\t/\tFALSE\t0\tSESSDATA\tother\n";
+        let cookies = parse_cookies(content);
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies["SESSDATA"], ("sess%2C1".to_owned(), 1700000001));
+        assert_eq!(cookies["bili_jct"], ("csrf".to_owned(), 1700000000));
+    }
+
+    #[test]
+    fn parse_cookie_header() {
+        let cookies = parse_cookies("Cookie: SESSDATA=abc; bili_jct=def");
+        assert_eq!(cookies["SESSDATA"], ("abc".to_owned(), 0));
+        assert_eq!(cookies["bili_jct"], ("def".to_owned(), 0));
+    }
+}